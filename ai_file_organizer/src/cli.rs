@@ -1,4 +1,15 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use crate::organizer::duplicates::KeepPolicy;
+use crate::organizer::hashing::HashAlgorithm;
+use crate::organizer::renamer::{CollisionPolicy, PlacementMode};
+use crate::config::AppConfig;
+use crate::organizer::transfer::LinkKind;
+use crate::organizer::report::ReportFormat;
+use crate::organizer::similar::ImageHash;
+use crate::organizer::scanner::{AgeBasis, AtomicPolicy, ScanOptions, SymlinkPolicy};
+use crate::organizer::utils::{parse_age, parse_fraction, parse_size};
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -9,6 +20,18 @@ pub struct Cli {
     /// Verbose logging
     #[arg(short, long)]
     pub verbose: bool,
+
+    /// Config file to use instead of the one in the user config directory
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+
+    /// Worker threads for scanning and hashing
+    #[arg(long, global = true)]
+    pub threads: Option<usize>,
+
+    /// Extract and hash every file afresh, without reading or updating the metadata cache
+    #[arg(long, global = true)]
+    pub no_cache: bool,
 }
 
 #[derive(Subcommand)]
@@ -17,6 +40,9 @@ pub enum Commands {
     Scan {
         /// Path to scan
         path: String,
+
+        #[command(flatten)]
+        filters: ScanArgs,
     },
     /// Organize files based on rules or AI
     Organize {
@@ -27,6 +53,10 @@ pub enum Commands {
         #[arg(long)]
         ai: bool,
 
+        /// Let the AI propose file names for files no rule renames (needs --ai)
+        #[arg(long, requires = "ai")]
+        ai_rename: bool,
+
         /// Path to rules file
         #[arg(long)]
         rules: Option<String>,
@@ -34,6 +64,34 @@ pub enum Commands {
         /// Dry run mode
         #[arg(long)]
         dry_run: bool,
+
+        /// Write the planned moves to this JSON file instead of applying them
+        #[arg(long)]
+        plan_out: Option<String>,
+
+        /// Destination folder template, e.g. "{category}/{year}/{quarter}"
+        #[arg(long)]
+        pattern: Option<String>,
+
+        /// What to do when a destination already exists
+        #[arg(long, value_enum, default_value_t = CollisionPolicy::Suffix)]
+        on_collision: CollisionPolicy,
+
+        /// How files get into the organized tree; rules may set their own
+        #[arg(long, value_enum, default_value_t = PlacementMode::Move)]
+        mode: PlacementMode,
+
+        /// Skip project directories and bundles, or move each whole into `Projects`
+        #[arg(long, value_enum)]
+        atomic_dirs: Option<AtomicPolicy>,
+
+        #[command(flatten)]
+        filters: ScanArgs,
+    },
+    /// Execute a plan written by `organize --plan-out`
+    Apply {
+        /// Path to the plan file
+        plan: String,
     },
     /// Find and manage duplicate files
     Duplicates {
@@ -41,23 +99,236 @@ pub enum Commands {
         path: String,
 
         /// Remove duplicates automatically
-        #[arg(long)]
+        #[arg(long, conflicts_with = "link")]
         remove: bool,
 
+        /// Delete for good instead of moving to the trash
+        #[arg(long, requires = "remove")]
+        permanent: bool,
+
+        /// Replace duplicates with links to the kept copy instead of deleting them
+        #[arg(long, value_enum, value_name = "KIND")]
+        link: Option<LinkKind>,
+
+        /// Compare pictures by appearance, so resized, recompressed or re-exported copies match
+        #[arg(long, conflicts_with = "link")]
+        similar_images: bool,
+
+        /// Compare the words of text, PDF and office documents, so the same content in other formats matches
+        #[arg(long, conflicts_with_all = ["link", "similar_images"])]
+        similar_text: bool,
+
+        /// Perceptual hash for `--similar-images`
+        #[arg(long, value_enum, default_value_t = ImageHash::Phash)]
+        image_hash: ImageHash,
+
+        /// How alike two files must be, as a fraction like 0.9 or 90% [default: 0.85 for pictures, 0.9 for text]
+        #[arg(long, value_name = "SIMILARITY", value_parser = parse_fraction)]
+        threshold: Option<f64>,
+
         /// Dry run mode
         #[arg(long)]
         dry_run: bool,
+
+        /// Only look for files already present in this reference library, which is never modified
+        #[arg(long, value_name = "LIBRARY")]
+        against: Option<PathBuf>,
+
+        /// How to list the sets found; sorted by reclaimable space
+        #[arg(long, value_enum, default_value_t = ReportFormat::Text, conflicts_with_all = ["remove", "link"])]
+        format: ReportFormat,
+
+        /// Hash algorithm; overrides `hash_algorithm` from the config
+        #[arg(long, value_enum)]
+        hash: Option<HashAlgorithm>,
+
+        /// Confirm every set byte by byte after hashing
+        #[arg(long, conflicts_with_all = ["similar_images", "similar_text"])]
+        verify: bool,
+
+        /// Which copy `--remove` keeps [default: newest, or highest-resolution with `--similar-images`]
+        #[arg(long, value_enum)]
+        keep: Option<KeepPolicy>,
+
+        /// Keep the copy inside this folder, whatever `--keep` says (repeatable)
+        #[arg(long, value_name = "DIR")]
+        prefer: Vec<PathBuf>,
+
+        /// Never delete anything inside this folder (repeatable)
+        #[arg(long, value_name = "DIR")]
+        protect: Vec<PathBuf>,
+
+        #[command(flatten)]
+        filters: ScanArgs,
     },
-    /// Undo latest organization
+    /// Undo an organization session (the latest by default)
     Undo {
         /// Root path
         path: String,
+
+        /// Session id to roll back, or `list` to show the journal
+        target: Option<String>,
+
+        /// Roll back a single file, given its original or current path
+        #[arg(long, conflicts_with = "target")]
+        file: Option<String>,
+
+        /// Restore files even if they changed since they were moved
+        #[arg(long)]
+        force: bool,
+    },
+    /// Re-apply an undone session (the most recently undone by default)
+    Redo {
+        /// Root path
+        path: String,
+
+        /// Session id to re-apply
+        session: Option<u64>,
+
+        /// Move files even if they changed since they were restored
+        #[arg(long)]
+        force: bool,
+    },
+    /// Finish or roll back an organize run that was interrupted
+    Recover {
+        /// Root path
+        path: String,
+
+        /// Move already-organized files back instead of finishing the run
+        #[arg(long)]
+        rollback: bool,
     },
     /// Watch folder for new files and organize them
     Watch {
         /// Path to watch
         path: String,
+
+        #[command(flatten)]
+        filters: ScanArgs,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Inspect or clean up the metadata cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
     },
     /// Launch GUI
     Gui,
 }
+
+/// Which files a command looks at; shared by every command that walks a folder.
+#[derive(Args, Debug, Clone, Default)]
+pub struct ScanArgs {
+    /// Only consider files matching this glob (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Skip files and folders matching this glob (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Skip files less than this deep (1 = directly inside the folder)
+    #[arg(long, value_name = "N")]
+    pub min_depth: Option<usize>,
+
+    /// Do not look deeper than this (1 = only the folder itself)
+    #[arg(long, value_name = "N")]
+    pub max_depth: Option<usize>,
+
+    /// Only files larger than this, e.g. 100M, 1.5G, 512k
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub larger_than: Option<u64>,
+
+    /// Only files smaller than this
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub smaller_than: Option<u64>,
+
+    /// Only files older than this, e.g. 30d, 12h, 2w, 6mo, 1y
+    #[arg(long, value_name = "AGE", value_parser = parse_age)]
+    pub older_than: Option<Duration>,
+
+    /// Only files newer than this
+    #[arg(long, value_name = "AGE", value_parser = parse_age)]
+    pub newer_than: Option<Duration>,
+
+    /// Timestamp the age filters look at
+    #[arg(long, value_enum, default_value_t = AgeBasis::Mtime)]
+    pub age_by: AgeBasis,
+
+    /// Skip symlinks, follow them, or treat each link as a file
+    #[arg(long, value_enum, default_value_t = SymlinkPolicy::Skip)]
+    pub symlinks: SymlinkPolicy,
+
+    /// Stay on the folder's filesystem; never descend into mounts
+    #[arg(long)]
+    pub one_file_system: bool,
+}
+
+impl ScanArgs {
+    /// Combines the flags with the configured `ignore_list` and atomic markers.
+    pub fn options(self, settings: &AppConfig) -> ScanOptions {
+        ScanOptions {
+            ignore_list: settings.ignore_list.clone(),
+            atomic_markers: settings.atomic_markers.clone(),
+            include: self.include,
+            exclude: self.exclude,
+            min_depth: self.min_depth,
+            max_depth: self.max_depth,
+            larger_than: self.larger_than,
+            smaller_than: self.smaller_than,
+            older_than: self.older_than,
+            newer_than: self.newer_than,
+            age_by: self.age_by,
+            symlinks: self.symlinks,
+            one_file_system: self.one_file_system,
+            atomic_units: false,
+        }
+    }
+}
+
+impl Commands {
+    /// The folder a command works on, whose `.organizer.toml` applies.
+    pub fn root(&self) -> Option<&str> {
+        match self {
+            Commands::Scan { path, .. }
+            | Commands::Organize { path, .. }
+            | Commands::Duplicates { path, .. }
+            | Commands::Undo { path, .. }
+            | Commands::Redo { path, .. }
+            | Commands::Recover { path, .. }
+            | Commands::Watch { path, .. }
+            | Commands::Config { action: ConfigAction::Show { path, .. } } => Some(path),
+            Commands::Apply { .. } | Commands::Cache { .. } | Commands::Gui => None,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum ConfigAction {
+    /// Show which config files apply to a folder
+    Show {
+        /// Folder whose `.organizer.toml` is layered in
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Print the merged settings and where each one came from
+        #[arg(long)]
+        effective: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CacheAction {
+    /// Show the cache location, size and how many entries are stale
+    Stats,
+    /// Drop entries for files that were deleted or changed
+    Prune {
+        /// Also drop entries not seen by any scan for this long (e.g. 90d)
+        #[arg(long, value_parser = parse_age, value_name = "AGE")]
+        older_than: Option<Duration>,
+    },
+}
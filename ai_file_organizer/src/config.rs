@@ -1,12 +1,27 @@
+use crate::constants::{APP_DIR_NAME, ENV_PREFIX, ROOT_CONFIG_FILE};
+use crate::organizer::companions::{self, CompanionRule};
+use crate::organizer::hashing::HashAlgorithm;
+use crate::organizer::scanner::AtomicPolicy;
+use anyhow::{Context, Result, bail};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
-#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AppConfig {
     pub openai_api_key: Option<String>,
     pub ignore_list: Vec<String>,
     pub default_output_pattern: String,
     pub thread_count: usize,
+    /// Entries that mark a directory as one unit, never split up by `organize`
+    pub atomic_markers: Vec<String>,
+    /// Skip atomic directories or move them whole into `Projects`
+    pub atomic_dirs: AtomicPolicy,
+    /// Sidecars (XMP, subtitles, split archive parts) that move with their primary file
+    pub companion_rules: Vec<CompanionRule>,
+    /// Content hash for duplicate detection
+    pub hash_algorithm: HashAlgorithm,
 }
 
 impl Default for AppConfig {
@@ -16,12 +31,217 @@ impl Default for AppConfig {
             ignore_list: vec![".git".to_string(), "node_modules".to_string()],
             default_output_pattern: "{category}/{year}/{month}".to_string(),
             thread_count: num_cpus::get(),
+            atomic_markers: [".git", ".hg", ".svn", "Cargo.toml", "package.json", "metadata.db", "*.photoslibrary", "*.app"]
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+            atomic_dirs: AtomicPolicy::Skip,
+            companion_rules: companions::default_rules(),
+            hash_algorithm: HashAlgorithm::Blake3,
         }
     }
 }
 
-#[allow(dead_code)]
-pub fn load_config() -> AppConfig {
-    // In a real app, load from disk
-    AppConfig::default()
+/// Names of all settings, including unset optional ones
+const SETTINGS: &[&str] = &[
+    "openai_api_key",
+    "ignore_list",
+    "default_output_pattern",
+    "thread_count",
+    "atomic_markers",
+    "atomic_dirs",
+    "companion_rules",
+    "hash_algorithm",
+];
+
+/// One layer of settings (a file, the environment or the command line). Unset
+/// fields fall through to the layer below.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLayer {
+    pub openai_api_key: Option<String>,
+    pub ignore_list: Option<Vec<String>>,
+    pub default_output_pattern: Option<String>,
+    pub thread_count: Option<usize>,
+    pub atomic_markers: Option<Vec<String>>,
+    pub atomic_dirs: Option<AtomicPolicy>,
+    pub companion_rules: Option<Vec<CompanionRule>>,
+    pub hash_algorithm: Option<HashAlgorithm>,
+}
+
+/// Where the effective value of a setting came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    UserFile(PathBuf),
+    RootFile(PathBuf),
+    Env(String),
+    Cli,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::UserFile(path) => write!(f, "user config {}", path.display()),
+            ConfigSource::RootFile(path) => write!(f, "folder config {}", path.display()),
+            ConfigSource::Env(var) => write!(f, "environment {}", var),
+            ConfigSource::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+/// The merged configuration together with the origin of every setting.
+#[derive(Debug, Clone)]
+pub struct LoadedConfig {
+    pub config: AppConfig,
+    pub sources: BTreeMap<&'static str, ConfigSource>,
+}
+
+/// Copies every field a layer sets and remembers who set it.
+macro_rules! merge_fields {
+    ($loaded:expr, $layer:expr, $source:expr; $($field:ident),+) => {
+        $(
+            if let Some(value) = $layer.$field {
+                $loaded.config.$field = value.into();
+                $loaded.sources.insert(stringify!($field), $source(stringify!($field)));
+            }
+        )+
+    };
+}
+
+impl LoadedConfig {
+    pub fn defaults() -> Self {
+        let sources = SETTINGS.iter().map(|name| (*name, ConfigSource::Default)).collect();
+        Self { config: AppConfig::default(), sources }
+    }
+
+    fn merge(&mut self, layer: ConfigLayer, source: impl Fn(&'static str) -> ConfigSource) {
+        merge_fields!(
+            self, layer, source;
+            openai_api_key,
+            ignore_list,
+            default_output_pattern,
+            thread_count,
+            atomic_markers,
+            atomic_dirs,
+            companion_rules,
+            hash_algorithm
+        );
+    }
+
+    /// Prints the merged settings, one per line, with their origin. The API key is masked.
+    pub fn print_effective(&self) -> Result<()> {
+        let mut values = toml::Table::try_from(&self.config)?;
+        if let Some(key) = values.get_mut("openai_api_key") {
+            *key = toml::Value::String("********".to_string());
+        }
+        for (name, source) in &self.sources {
+            let value = values
+                .get(*name)
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| "(unset)".to_string());
+            println!("{} = {}    # {}", name, value, source);
+        }
+        Ok(())
+    }
+}
+
+/// `~/.config/ai_file_organizer/config.toml` (or the platform equivalent); a
+/// `config.json` next to it is used when there is no TOML file.
+pub fn user_config_path() -> Option<PathBuf> {
+    let dir = dirs::config_dir()?.join(APP_DIR_NAME);
+    let toml = dir.join("config.toml");
+    let json = dir.join("config.json");
+    Some(if !toml.exists() && json.exists() { json } else { toml })
 }
+
+/// Merges, lowest first: built-in defaults, the user config file (or `config_file`
+/// when given), `<root>/.organizer.toml`, `ORGANIZER_*` variables and `cli`.
+pub fn load(root: Option<&Path>, config_file: Option<&Path>, cli: ConfigLayer) -> Result<LoadedConfig> {
+    let mut loaded = LoadedConfig::defaults();
+
+    let user_file = config_file.map(Path::to_path_buf).or_else(user_config_path);
+    if let Some(path) = user_file {
+        if path.exists() || config_file.is_some() {
+            let layer = read_layer(&path)?;
+            loaded.merge(layer, |_| ConfigSource::UserFile(path.clone()));
+        }
+    }
+
+    if let Some(root) = root {
+        let path = root.join(ROOT_CONFIG_FILE);
+        if path.exists() {
+            let layer = read_layer(&path)?;
+            loaded.merge(layer, |_| ConfigSource::RootFile(path.clone()));
+        }
+    }
+
+    let layer = env_layer(&loaded.config)?;
+    loaded.merge(layer, |field| ConfigSource::Env(env_var(field)));
+
+    loaded.merge(cli, |_| ConfigSource::Cli);
+    Ok(loaded)
+}
+
+/// Lists the config files that apply to `root`, lowest precedence first.
+pub fn print_layers(root: &Path, config_file: Option<&Path>) {
+    let status = |path: &Path| if path.exists() { "found" } else { "not found" };
+    match config_file.map(Path::to_path_buf).or_else(user_config_path) {
+        Some(path) => println!("user config:   {} ({})", path.display(), status(&path)),
+        None => println!("user config:   (no config directory on this platform)"),
+    }
+    let path = root.join(ROOT_CONFIG_FILE);
+    println!("folder config: {} ({})", path.display(), status(&path));
+    let set: Vec<String> = SETTINGS.iter().map(|f| env_var(f)).filter(|v| std::env::var_os(v).is_some()).collect();
+    if set.is_empty() {
+        println!("environment:   no {}* variables set", ENV_PREFIX);
+    } else {
+        println!("environment:   {}", set.join(", "));
+    }
+}
+
+fn read_layer(path: &Path) -> Result<ConfigLayer> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config at {:?}", path))?;
+    let layer = if path.extension().is_some_and(|e| e == "json") {
+        serde_json::from_str(&content).with_context(|| format!("Invalid config at {:?}", path))?
+    } else {
+        toml::from_str(&content).with_context(|| format!("Invalid config at {:?}", path))?
+    };
+    Ok(layer)
+}
+
+fn env_var(field: &str) -> String {
+    format!("{}{}", ENV_PREFIX, field.to_uppercase())
+}
+
+/// Reads `ORGANIZER_<FIELD>` for every field. Lists are comma separated and the
+/// type of each value follows the default configuration.
+fn env_layer(current: &AppConfig) -> Result<ConfigLayer> {
+    let defaults = toml::Table::try_from(current)?;
+    let mut table = toml::Table::new();
+    for field in SETTINGS {
+        let var = env_var(field);
+        let Ok(raw) = std::env::var(&var) else {
+            continue;
+        };
+        let value = match defaults.get(*field) {
+            Some(toml::Value::Array(_)) => toml::Value::Array(
+                raw.split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| toml::Value::String(s.to_string()))
+                    .collect(),
+            ),
+            Some(toml::Value::Integer(_)) => match raw.trim().parse() {
+                Ok(n) => toml::Value::Integer(n),
+                Err(_) => bail!("{} must be a number, got {:?}", var, raw),
+            },
+            _ => toml::Value::String(raw),
+        };
+        table.insert(field.to_string(), value);
+    }
+    Ok(table.try_into()?)
+}
+
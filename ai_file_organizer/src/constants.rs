@@ -4,9 +4,28 @@ pub const APP_VERSION: &str = "0.1.0";
 #[allow(dead_code)]
 pub const DEFAULT_CONFIG_FILE: &str = "config/default_rules.json";
 pub const UNDO_HISTORY_FILE: &str = ".undo_history.json";
+pub const INTENT_LOG_FILE: &str = ".organizer_wal.jsonl";
+pub const LOCK_FILE: &str = ".organizer.lock";
+/// Per-folder settings, layered over the user config
+pub const ROOT_CONFIG_FILE: &str = ".organizer.toml";
+/// Gitignore-style skip list, honoured in the folder that holds it and below
+pub const IGNORE_FILE: &str = ".organizerignore";
+/// Directory under the platform config and cache dirs holding our files
+pub const APP_DIR_NAME: &str = "ai_file_organizer";
+/// Environment variables overriding settings are `ORGANIZER_<SETTING>`
+pub const ENV_PREFIX: &str = "ORGANIZER_";
+/// Metadata cache, under the platform cache dir
+pub const CACHE_FILE: &str = "metadata_cache.json";
+/// Longest file name most filesystems accept, in bytes
+pub const MAX_FILE_NAME_BYTES: usize = 255;
+/// Bookkeeping files the organizer keeps under the root; never organized themselves
+pub const INTERNAL_FILES: &[&str] = &[UNDO_HISTORY_FILE, INTENT_LOG_FILE, LOCK_FILE, ROOT_CONFIG_FILE, IGNORE_FILE];
 #[allow(dead_code)]
 pub const DEFAULT_MODELS_DIR: &str = "models";
 
+/// Category atomic directories are moved into
+pub const PROJECTS_CATEGORY: &str = "Projects";
+
 #[allow(dead_code)]
 pub const CATEGORIES: &[&str] = &[
     "Work",
@@ -59,6 +59,13 @@ impl eframe::App for App {
                 }
                 if ui.button("Undo").clicked() {
                     self.logs.push("Undoing last operation...".to_string());
+                    let root = std::path::Path::new(&self.path);
+                    let undone = crate::organizer::lock_and_recover(root)
+                        .and_then(|_lock| crate::organizer::undo::undo(root, None, false));
+                    match undone {
+                        Ok(()) => self.logs.push("Undo complete".to_string()),
+                        Err(e) => self.logs.push(format!("Undo failed: {}", e)),
+                    }
                 }
             });
 
@@ -6,53 +6,227 @@ mod models;
 mod organizer;
 
 use clap::Parser;
-use cli::{Cli, Commands};
-use std::path::Path;
-use anyhow::Result;
+use cli::{CacheAction, Cli, Commands, ConfigAction};
+use organizer::duplicates::KeepPolicy;
+use organizer::hashing::HashAlgorithm;
+use organizer::renamer::CollisionPolicy;
+use organizer::report::ReportFormat;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result, bail};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
     let cli = Cli::parse();
 
+    let overrides = config::ConfigLayer {
+        thread_count: cli.threads,
+        default_output_pattern: match &cli.command {
+            Commands::Organize { pattern, .. } => pattern.clone(),
+            _ => None,
+        },
+        atomic_dirs: match &cli.command {
+            Commands::Organize { atomic_dirs, .. } => *atomic_dirs,
+            _ => None,
+        },
+        hash_algorithm: match &cli.command {
+            Commands::Duplicates { hash, .. } => *hash,
+            _ => None,
+        },
+        ..Default::default()
+    };
+    let config_file = cli.config.as_deref().map(Path::new);
+    let loaded = match config::load(cli.command.root().map(Path::new), config_file, overrides) {
+        Ok(loaded) => loaded,
+        // A broken config must not stand in the way of putting files back
+        Err(e) if matches!(cli.command, Commands::Undo { .. } | Commands::Redo { .. } | Commands::Recover { .. }) => {
+            eprintln!("Warning: ignoring configuration: {:#}", e);
+            config::LoadedConfig::defaults()
+        }
+        Err(e) => return Err(e),
+    };
+    let settings = loaded.config.clone();
+    rayon::ThreadPoolBuilder::new().num_threads(settings.thread_count).build_global()?;
+
     match cli.command {
-        Commands::Scan { path } => {
+        Commands::Scan { path, filters } => {
             println!("Scanning path: {}", path);
-            let files = organizer::scanner::scan(Path::new(&path))?;
+            let options = filters.options(&settings);
+            let files = organizer::scanner::scan(Path::new(&path), &options)?;
             println!("Found {} files", files.len());
+            let mut cache = organizer::cache::MetadataCache::open(!cli.no_cache);
             for mut file in files {
-                let _ = organizer::metadata::extract_metadata(&mut file);
+                let _ = cache.extract(&mut file);
                 println!("- {} ({})", file.name, file.metadata.mime_type);
             }
+            cache.save()?;
         }
-        Commands::Organize { path, ai, rules, dry_run } => {
+        Commands::Organize { path, ai, ai_rename, rules, dry_run, plan_out, pattern: _, on_collision, mode, filters, atomic_dirs: _ } => {
+            if on_collision == CollisionPolicy::OverwriteIfOlder && !organizer::trash::AVAILABLE {
+                bail!("--on-collision overwrite-if-older moves replaced files to the trash, which is not supported on this platform");
+            }
             println!("Organizing path: {}", path);
-            let organizer = organizer::Organizer::new(dry_run, ai, rules);
+            let organizer = organizer::Organizer::new(&settings, dry_run, ai, rules)
+                .with_plan_out(plan_out.map(PathBuf::from))
+                .with_ai_rename(ai_rename)
+                .with_collision_policy(on_collision)
+                .with_mode(mode)
+                .with_scan_options(filters.options(&settings))
+                .with_cache(!cli.no_cache);
             organizer.process(Path::new(&path)).await?;
         }
-        Commands::Duplicates { path, remove, dry_run } => {
-            println!("Checking for duplicates in: {}", path);
-            let mut files = organizer::scanner::scan(Path::new(&path))?;
-            let duplicates = organizer::duplicates::find_duplicates(&mut files)?;
-            
-            if remove {
-                organizer::duplicates::remove_duplicates(&duplicates, dry_run)?;
+        Commands::Apply { plan } => {
+            println!("Applying plan: {}", plan);
+            let plan = organizer::plan::Plan::load(Path::new(&plan))?;
+            let _lock = organizer::lock_and_recover(&plan.root)?;
+            organizer::plan::apply(&plan)?;
+        }
+        Commands::Duplicates {
+            path, remove, permanent, link, similar_images, similar_text, image_hash, threshold, dry_run, against, format, hash: _, verify: cli_verify, keep, prefer, protect, filters,
+        } => {
+            if remove && link.is_none() && !permanent && !organizer::trash::AVAILABLE {
+                bail!("--remove moves duplicates to the trash, which is not supported on this platform; add --permanent to delete them");
+            }
+            // Journal entries need absolute paths
+            let root = std::fs::canonicalize(&path)?;
+            let library = match against {
+                Some(dir) => Some(
+                    std::fs::canonicalize(&dir).with_context(|| format!("Cannot find library {:?}", dir))?,
+                ),
+                None => None,
+            };
+            let (mut prefer, mut protect) = (prefer, protect);
+            if let Some(library) = &library {
+                if library.starts_with(&root) || root.starts_with(library) {
+                    bail!("{:?} and the library {:?} must not contain one another", root, library);
+                }
+                // A hardlink would make the inbox file another name of the library file,
+                // so editing one would change the library
+                if link == Some(organizer::transfer::LinkKind::Hardlink) {
+                    bail!("--link hardlink would tie incoming files to the library; use reflink or symlink with --against");
+                }
+                // The library copy is the one kept, and no library file is ever touched
+                prefer.push(library.clone());
+                protect.push(library.clone());
+            }
+            let default_keep = if similar_images { KeepPolicy::HighestResolution } else { KeepPolicy::Newest };
+            let rules = organizer::duplicates::KeepRules::new(keep.unwrap_or(default_keep), prefer, protect)?;
+            // Keep stdout clean for machine-readable reports
+            match &library {
+                Some(library) => eprintln!("Checking {} against library {:?}", path, library),
+                None => eprintln!("Checking for duplicates in: {}", path),
+            }
+            let changes = (remove || link.is_some()) && !dry_run;
+            // A 64-bit xxh3 match is not proof enough to delete or relink a file
+            let verify = cli_verify || ((remove || link.is_some()) && settings.hash_algorithm == HashAlgorithm::Xxh3);
+            if verify && !cli_verify {
+                eprintln!("xxh3 is not collision resistant; confirming every set byte by byte");
+            }
+            let _lock = if changes { Some(organizer::lock_and_recover(&root)?) } else { None };
+            let options = filters.options(&settings);
+            let mut files = organizer::scanner::scan(&root, &options)?;
+            let incoming: Vec<PathBuf> = files.iter().map(|f| f.path.clone()).collect();
+            if let Some(library) = &library {
+                files.extend(organizer::scanner::scan(library, &options)?);
+            }
+            let mut cache = organizer::cache::MetadataCache::open(!cli.no_cache);
+            let duplicates = if similar_images {
+                let threshold = threshold.unwrap_or(organizer::similar::DEFAULT_IMAGE_THRESHOLD);
+                organizer::similar::find_similar_images(files, &mut cache, image_hash, threshold)?
+            } else if similar_text {
+                let threshold = threshold.unwrap_or(organizer::similar::DEFAULT_TEXT_THRESHOLD);
+                organizer::similar::find_similar_text(files, &mut cache, threshold)?
+            } else {
+                organizer::duplicates::find_duplicates(files, &mut cache, settings.hash_algorithm, verify)?
+            };
+            cache.save()?;
+            let duplicates = match &library {
+                Some(library) => organizer::duplicates::known_to_library(duplicates, library),
+                None => duplicates,
+            };
+
+            if let Some(kind) = link {
+                organizer::duplicates::link_duplicates(&root, &duplicates, &rules, kind, dry_run)?;
+            } else if remove {
+                organizer::duplicates::remove_duplicates(&root, &duplicates, &rules, permanent, dry_run)?;
             } else {
-                for (hash, set) in duplicates {
-                    println!("Hash {}: {} duplicates", hash, set.len());
-                    for f in set {
-                        println!("  - {:?}", f.path);
+                let sets = organizer::report::build(&duplicates, &rules);
+                organizer::report::write(&sets, format, &mut std::io::stdout().lock())?;
+                if library.is_some() && format == ReportFormat::Text {
+                    let known: HashSet<&Path> =
+                        duplicates.values().flatten().map(|f| f.path.as_path()).collect();
+                    let mut new: Vec<&PathBuf> = incoming.iter().filter(|p| !known.contains(p.as_path())).collect();
+                    new.sort();
+                    println!("New, not in the library: {} file(s)", new.len());
+                    for path in new {
+                        println!("  + {:?}", path);
                     }
                 }
             }
         }
-        Commands::Undo { path } => {
-            println!("Undoing changes in: {}", path);
-            organizer::undo::undo(Path::new(&path))?;
+        Commands::Undo { path, target, file, force } => {
+            let root = Path::new(&path);
+            // Organize rewrites the history as it goes; never undo behind a live run
+            let _lock = organizer::lock_and_recover(root)?;
+            match (target.as_deref(), file) {
+                (Some("list"), _) => organizer::undo::list(root)?,
+                (_, Some(file)) => {
+                    println!("Undoing move of {} in: {}", file, path);
+                    organizer::undo::undo_file(root, Path::new(&file), force)?;
+                }
+                (Some(id), None) => {
+                    let id = id
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("Expected a session id or `list`, got {:?}", id))?;
+                    println!("Undoing session #{} in: {}", id, path);
+                    organizer::undo::undo(root, Some(id), force)?;
+                }
+                (None, None) => {
+                    println!("Undoing changes in: {}", path);
+                    organizer::undo::undo(root, None, force)?;
+                }
+            }
+        }
+        Commands::Redo { path, session, force } => {
+            println!("Redoing changes in: {}", path);
+            let root = Path::new(&path);
+            let _lock = organizer::lock_and_recover(root)?;
+            organizer::undo::redo(root, session, force)?;
         }
-        Commands::Watch { path } => {
+        Commands::Recover { path, rollback } => {
+            println!("Recovering: {}", path);
+            let root = Path::new(&path);
+            let _lock = organizer::lock::RootLock::acquire(root)?;
+            let action = if rollback {
+                organizer::wal::RecoverAction::Rollback
+            } else {
+                organizer::wal::RecoverAction::Finish
+            };
+            organizer::wal::recover(root, action)?;
+        }
+        Commands::Watch { path, filters } => {
             println!("Watching path: {}", path);
-            organizer::watcher::watch_folder(Path::new(&path))?;
+            // Events carry absolute paths, so the filter needs an absolute root
+            let root = std::fs::canonicalize(&path)?;
+            let _lock = organizer::lock_and_recover(&root)?;
+            organizer::watcher::watch_folder(&root, &filters.options(&settings))?;
+        }
+        Commands::Config { action: ConfigAction::Show { path, effective } } => {
+            config::print_layers(Path::new(&path), config_file);
+            if effective {
+                println!();
+                loaded.print_effective()?;
+            }
+        }
+        Commands::Cache { action: CacheAction::Stats } => {
+            organizer::cache::MetadataCache::open(true).print_stats();
+        }
+        Commands::Cache { action: CacheAction::Prune { older_than } } => {
+            let mut cache = organizer::cache::MetadataCache::open(true);
+            let removed = cache.prune(older_than);
+            cache.save()?;
+            println!("Removed {} cache entr{}", removed, if removed == 1 { "y" } else { "ies" });
         }
         Commands::Gui => {
             if let Err(e) = gui::run_gui() {
@@ -1,3 +1,4 @@
+use crate::organizer::hashing::HashAlgorithm;
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
@@ -10,6 +11,9 @@ pub struct FileMetadata {
     pub modified: Option<DateTime<Local>>,
     pub size: u64,
     pub hash: String,
+    /// Algorithm that produced `hash`; records from before it was stored are blake3
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
     pub extra: std::collections::HashMap<String, String>,
 }
 
@@ -40,6 +44,7 @@ impl FileInfo {
                 modified: None,
                 size: 0,
                 hash: "".to_string(),
+                hash_algorithm: HashAlgorithm::default(),
                 extra: std::collections::HashMap::new(),
             },
             category: None,
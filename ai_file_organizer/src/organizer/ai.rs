@@ -1,6 +1,11 @@
 use crate::models::FileInfo;
 use anyhow::{Result, anyhow};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// `extra` keys that mean nothing to the model: the text goes in separately, and
+/// signatures and hashes are noise
+const HIDDEN_KEYS: [&str; 5] = ["text", "minhash", "phash", "dhash", "similarity"];
 
 #[derive(Debug, Serialize)]
 struct OpenAIRequest {
@@ -39,16 +44,34 @@ impl AIClassifier {
     }
 
     pub async fn classify(&self, file: &FileInfo) -> Result<String> {
-        let client = reqwest::Client::new();
         let prompt = format!(
             "Classify the following file into one of these categories: Work, Personal, Finance, Media, Code, Screenshots, Notes, Archives. 
-            File Name: {}
-            MIME Type: {}
-            Metadata: {:?}
+            {}
             Only return the category name.",
-            file.name, file.metadata.mime_type, file.metadata.extra
+            describe(file)
         );
+        self.complete(prompt).await
+    }
 
+    /// Proposes a descriptive file name, keeping the original extension.
+    pub async fn suggest_name(&self, file: &FileInfo) -> Result<String> {
+        let prompt = format!(
+            "Suggest a short, descriptive file name for the following file.
+            {}
+            Only return the name, without an extension.",
+            describe(file)
+        );
+        let stem = self.complete(prompt).await?;
+        let stem = stem.trim_matches(|c: char| c == '"' || c == '\'' || c.is_whitespace());
+        if file.metadata.extension.is_empty() {
+            Ok(stem.to_string())
+        } else {
+            Ok(format!("{}.{}", stem, file.metadata.extension))
+        }
+    }
+
+    async fn complete(&self, prompt: String) -> Result<String> {
+        let client = reqwest::Client::new();
         let response = client
             .post("https://api.openai.com/v1/chat/completions")
             .header("Authorization", format!("Bearer {}", self.api_key))
@@ -63,10 +86,25 @@ impl AIClassifier {
             .await?;
 
         let res_body: OpenAIResponse = response.json().await?;
-        let category = res_body.choices.first()
+        let content = res_body.choices.first()
             .ok_or_else(|| anyhow!("No response from AI"))?
             .message.content.trim().to_string();
 
-        Ok(category)
+        Ok(content)
+    }
+}
+
+/// Name, type and metadata of the file for a prompt, plus the start of its text
+/// when any was extracted.
+fn describe(file: &FileInfo) -> String {
+    let metadata: BTreeMap<&String, &String> =
+        file.metadata.extra.iter().filter(|(key, _)| !HIDDEN_KEYS.contains(&key.as_str())).collect();
+    let mut description = format!(
+        "File Name: {}\nMIME Type: {}\nMetadata: {:?}",
+        file.name, file.metadata.mime_type, metadata
+    );
+    if let Some(text) = file.metadata.extra.get("text") {
+        description.push_str(&format!("\nText Excerpt: {}", text));
     }
+    description
 }
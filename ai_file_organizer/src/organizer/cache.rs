@@ -0,0 +1,205 @@
+use crate::constants::{APP_DIR_NAME, CACHE_FILE};
+use crate::models::file_info::FileMetadata;
+use crate::models::FileInfo;
+use crate::organizer::metadata;
+use crate::organizer::utils::format_size;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Raised whenever `extract_metadata` learns new fields, so older entries are
+/// extracted again
+const EXTRACTOR_VERSION: u32 = 1;
+
+/// Extracted metadata of one file, valid while its size and mtime are unchanged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Where the file was last seen; the key survives renames, this is informational
+    path: PathBuf,
+    size: u64,
+    mtime_secs: i64,
+    mtime_nanos: u32,
+    metadata: FileMetadata,
+    last_seen: DateTime<Local>,
+    #[serde(default)]
+    version: u32,
+}
+
+impl CacheEntry {
+    fn new(file: &FileInfo, attr: &Metadata) -> Self {
+        let (mtime_secs, mtime_nanos) = mtime(attr);
+        Self {
+            path: file.path.clone(),
+            size: attr.len(),
+            mtime_secs,
+            mtime_nanos,
+            metadata: file.metadata.clone(),
+            last_seen: Local::now(),
+            version: EXTRACTOR_VERSION,
+        }
+    }
+
+    fn is_fresh(&self, attr: &Metadata) -> bool {
+        self.version == EXTRACTOR_VERSION
+            && self.size == attr.len()
+            && (self.mtime_secs, self.mtime_nanos) == mtime(attr)
+    }
+}
+
+/// Persistent `FileMetadata` cache keyed by device and inode (by path on Windows),
+/// so files whose size and mtime did not change are never reopened.
+pub struct MetadataCache {
+    path: Option<PathBuf>,
+    entries: HashMap<String, CacheEntry>,
+    dirty: bool,
+    hits: usize,
+    misses: usize,
+}
+
+impl MetadataCache {
+    /// Loads the cache from the user cache directory. A disabled cache (`--no-cache`)
+    /// extracts everything afresh and never writes.
+    pub fn open(enabled: bool) -> Self {
+        let path = if enabled { default_path() } else { None };
+        let entries = match path.as_deref().filter(|p| p.exists()).map(read_entries) {
+            Some(Ok(entries)) => entries,
+            Some(Err(e)) => {
+                log::warn!("Ignoring unreadable metadata cache: {:#}", e);
+                HashMap::new()
+            }
+            None => HashMap::new(),
+        };
+        Self { path, entries, dirty: false, hits: 0, misses: 0 }
+    }
+
+    /// Fills `file.metadata` from the cache, or extracts it and remembers the result.
+    pub fn extract(&mut self, file: &mut FileInfo) -> Result<()> {
+        if !self.lookup(file)? {
+            metadata::extract_metadata(file)?;
+            self.insert(file)?;
+        }
+        Ok(())
+    }
+
+    /// Fills `file.metadata` from a fresh cache entry. Returns false on a miss,
+    /// leaving the file untouched.
+    pub fn lookup(&mut self, file: &mut FileInfo) -> Result<bool> {
+        if self.path.is_none() {
+            self.misses += 1;
+            return Ok(false);
+        }
+        let attr = fs::metadata(&file.path)?;
+        match self.entries.get_mut(&file_key(&file.path, &attr)) {
+            Some(entry) if entry.is_fresh(&attr) => {
+                file.metadata = entry.metadata.clone();
+                // Path-derived values follow the file if it was renamed
+                file.metadata.extension = file
+                    .path
+                    .extension()
+                    .map(|e| e.to_string_lossy().to_lowercase())
+                    .unwrap_or_default();
+                entry.path = file.path.clone();
+                entry.last_seen = Local::now();
+                self.hits += 1;
+                self.dirty = true;
+                Ok(true)
+            }
+            _ => {
+                self.misses += 1;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Remembers metadata extracted outside the cache, e.g. on worker threads.
+    pub fn insert(&mut self, file: &FileInfo) -> Result<()> {
+        if self.path.is_some() {
+            let attr = fs::metadata(&file.path)?;
+            self.entries.insert(file_key(&file.path, &attr), CacheEntry::new(file, &attr));
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Writes the cache back if anything changed, and logs the hit rate.
+    pub fn save(&self) -> Result<()> {
+        log::info!("Metadata cache: {} hit(s), {} miss(es)", self.hits, self.misses);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write then rename so a crash mid-write never leaves a truncated cache
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string(&self.entries)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    pub fn print_stats(&self) {
+        let Some(path) = &self.path else {
+            println!("No cache directory on this platform");
+            return;
+        };
+        let on_disk = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        let indexed: u64 = self.entries.values().map(|e| e.size).sum();
+        let hashed = self.entries.values().filter(|e| !e.metadata.hash.is_empty()).count();
+        let stale = self.entries.iter().filter(|(key, entry)| !is_current(key, entry)).count();
+        println!("Cache file: {:?} ({})", path, format_size(on_disk));
+        println!("Entries:    {} ({} with a content hash)", self.entries.len(), hashed);
+        println!("Indexed:    {}", format_size(indexed));
+        println!("Stale:      {} (removed by `cache prune`)", stale);
+    }
+
+    /// Drops entries whose file is gone or changed, and those not seen within
+    /// `unseen_for` when given. Returns how many were removed.
+    pub fn prune(&mut self, unseen_for: Option<Duration>) -> usize {
+        let cutoff = unseen_for.and_then(|age| chrono::Duration::from_std(age).ok()).map(|age| Local::now() - age);
+        let before = self.entries.len();
+        self.entries
+            .retain(|key, entry| is_current(key, entry) && cutoff.is_none_or(|cutoff| entry.last_seen >= cutoff));
+        let removed = before - self.entries.len();
+        self.dirty |= removed > 0;
+        removed
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    Some(dirs::cache_dir()?.join(APP_DIR_NAME).join(CACHE_FILE))
+}
+
+fn read_entries(path: &Path) -> Result<HashMap<String, CacheEntry>> {
+    let content = fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    serde_json::from_str(&content).with_context(|| format!("Invalid cache at {:?}", path))
+}
+
+/// Whether the entry still describes the file at its recorded path.
+fn is_current(key: &str, entry: &CacheEntry) -> bool {
+    fs::metadata(&entry.path).is_ok_and(|attr| file_key(&entry.path, &attr) == key && entry.is_fresh(&attr))
+}
+
+fn mtime(attr: &Metadata) -> (i64, u32) {
+    match attr.modified().map(|t| t.duration_since(UNIX_EPOCH)) {
+        Ok(Ok(since)) => (since.as_secs() as i64, since.subsec_nanos()),
+        _ => (0, 0),
+    }
+}
+
+#[cfg(unix)]
+fn file_key(_path: &Path, attr: &Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+    format!("{}:{}", attr.dev(), attr.ino())
+}
+
+#[cfg(windows)]
+fn file_key(path: &Path, _attr: &Metadata) -> String {
+    path.to_string_lossy().to_string()
+}
@@ -1,37 +1,86 @@
 use crate::models::FileInfo;
-use crate::organizer::rules::{RulesConfig, classify_by_rules};
+use crate::organizer::rules::{RulesConfig, find_matching_rule};
 use crate::organizer::ai::AIClassifier;
+use crate::organizer::renamer::PlacementMode;
 use anyhow::Result;
 
-#[allow(dead_code)]
 pub enum ClassificationMode {
     Rules(RulesConfig),
     AI(AIClassifier),
     Hybrid(RulesConfig, AIClassifier),
 }
 
-#[allow(dead_code)]
+/// A category together with why it was chosen, so plans can be reviewed.
+#[derive(Debug, Clone)]
+pub struct Classification {
+    pub category: String,
+    pub reason: String,
+    /// Destination template of the matching rule, if it has its own
+    pub output_pattern: Option<String>,
+    /// File name template of the matching rule, if it renames files
+    pub rename_to: Option<String>,
+    /// Placement mode of the matching rule, if it overrides `--mode`
+    pub mode: Option<PlacementMode>,
+}
+
 pub struct Classifier {
     pub mode: ClassificationMode,
 }
 
 impl Classifier {
-    #[allow(dead_code)]
-    pub async fn classify(&self, file: &FileInfo) -> Result<Option<String>> {
+    /// Picks the mode from whatever is available; `None` when there is nothing to classify with.
+    pub fn from_parts(rules: Option<RulesConfig>, ai: Option<AIClassifier>) -> Option<Self> {
+        let mode = match (rules, ai) {
+            (Some(rules), Some(ai)) => ClassificationMode::Hybrid(rules, ai),
+            (Some(rules), None) => ClassificationMode::Rules(rules),
+            (None, Some(ai)) => ClassificationMode::AI(ai),
+            (None, None) => return None,
+        };
+        Some(Self { mode })
+    }
+
+    pub fn ai(&self) -> Option<&AIClassifier> {
+        match &self.mode {
+            ClassificationMode::AI(ai) | ClassificationMode::Hybrid(_, ai) => Some(ai),
+            ClassificationMode::Rules(_) => None,
+        }
+    }
+
+    pub async fn classify(&self, file: &FileInfo) -> Result<Option<Classification>> {
         match &self.mode {
             ClassificationMode::Rules(config) => {
-                Ok(classify_by_rules(file, config))
+                Ok(classify_with_rules(file, config))
             }
             ClassificationMode::AI(ai) => {
-                Ok(Some(ai.classify(file).await?))
+                Ok(Some(classify_with_ai(file, ai).await?))
             }
             ClassificationMode::Hybrid(config, ai) => {
-                if let Some(category) = classify_by_rules(file, config) {
-                    Ok(Some(category))
+                if let Some(classification) = classify_with_rules(file, config) {
+                    Ok(Some(classification))
                 } else {
-                    Ok(Some(ai.classify(file).await?))
+                    Ok(Some(classify_with_ai(file, ai).await?))
                 }
             }
         }
     }
 }
+
+fn classify_with_rules(file: &FileInfo, config: &RulesConfig) -> Option<Classification> {
+    find_matching_rule(file, config).map(|(index, rule)| Classification {
+        category: rule.move_to.clone(),
+        reason: format!("rule #{} ({})", index + 1, rule.describe()),
+        output_pattern: rule.output_pattern.clone(),
+        rename_to: rule.rename_to.clone(),
+        mode: rule.mode,
+    })
+}
+
+async fn classify_with_ai(file: &FileInfo, ai: &AIClassifier) -> Result<Classification> {
+    Ok(Classification {
+        category: ai.classify(file).await?,
+        reason: "AI classification".to_string(),
+        output_pattern: None,
+        rename_to: None,
+        mode: None,
+    })
+}
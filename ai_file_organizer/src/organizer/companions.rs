@@ -0,0 +1,241 @@
+use crate::models::FileInfo;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// Files that travel with a primary file of the same stem, e.g. RAW + JPEG + XMP.
+/// Extensions are lowercase; `#` matches any digit, for split archives (`r##`, `###`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompanionRule {
+    /// Extensions that can lead a group; earlier ones win
+    pub primary: Vec<String>,
+    /// Extensions that follow the primary wherever it goes
+    pub companions: Vec<String>,
+}
+
+impl CompanionRule {
+    fn new(primary: &[&str], companions: &[&str]) -> Self {
+        Self {
+            primary: primary.iter().map(|e| e.to_string()).collect(),
+            companions: companions.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+}
+
+pub fn default_rules() -> Vec<CompanionRule> {
+    vec![
+        // RAW + JPEG pairs and their edit sidecars
+        CompanionRule::new(
+            &["cr2", "cr3", "nef", "arw", "dng", "raf", "orf", "rw2", "heic", "jpg", "jpeg"],
+            &["jpg", "jpeg", "heic", "xmp", "aae"],
+        ),
+        CompanionRule::new(
+            &["mkv", "mp4", "avi", "mov", "m4v", "webm"],
+            &["srt", "sub", "idx", "ass", "ssa", "vtt", "nfo", "xmp"],
+        ),
+        CompanionRule::new(&["cue"], &["bin", "img", "wav", "flac", "ape"]),
+        // Split archives: name.part1.rar, name.r00, name.7z.001, name.z01
+        CompanionRule::new(&["rar", "zip", "001"], &["rar", "r##", "z##", "###"]),
+    ]
+}
+
+/// A primary file and the companions that move with it.
+pub struct FileGroup {
+    pub primary: FileInfo,
+    pub companions: Vec<FileInfo>,
+}
+
+/// A name without its last extension: `2024.01.05 party.jpg` gives `2024.01.05 party`.
+pub fn stem(name: &str) -> &str {
+    name.rsplit_once('.').map(|(stem, _)| stem).filter(|stem| !stem.is_empty()).unwrap_or(name)
+}
+
+/// Split archive volumes (`backup.part2.rar`) share the name before `.partN`.
+fn volume_base(stem: &str) -> Option<&str> {
+    let (base, part) = stem.rsplit_once('.')?;
+    let number = part.strip_prefix("part").or_else(|| part.strip_prefix("PART"))?;
+    (!number.is_empty() && number.bytes().all(|b| b.is_ascii_digit())).then_some(base)
+}
+
+/// The start of the primary's name that `companion` shares, which is swapped for the
+/// primary's new stem when the primary is renamed.
+pub fn shared_stem<'a>(primary: &'a str, companion: &str) -> &'a str {
+    let stem = stem(primary);
+    match companion.strip_prefix(stem) {
+        Some(rest) if rest.starts_with('.') => stem,
+        _ => volume_base(stem).unwrap_or(stem),
+    }
+}
+
+fn extension(name: &str) -> String {
+    name.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).unwrap_or_default()
+}
+
+fn matches(pattern: &str, ext: &str) -> bool {
+    pattern.len() == ext.len()
+        && pattern
+            .chars()
+            .zip(ext.chars())
+            .all(|(p, c)| p == c || (p == '#' && c.is_ascii_digit()))
+}
+
+/// Whether `candidate` travels with `primary`, whose extension is the rule's
+/// `rank`-th primary. Companions are named `<stem>.<ext>`, `<stem>.<tag>.<ext>` or
+/// `<primary name>.<ext>`, or are further volumes of the same split archive. Another
+/// primary only follows one that ranks higher (the JPEG of a RAW), never its equal,
+/// so two photos never pull each other in.
+fn follows(rule: &CompanionRule, primary: &str, rank: usize, candidate: &str) -> bool {
+    let ext = extension(candidate);
+    if !rule.companions.iter().any(|pattern| matches(pattern, &ext)) {
+        return false;
+    }
+    let (stem, own) = (stem(primary), stem(candidate));
+    let volume = volume_base(stem).is_some() && volume_base(stem) == volume_base(own);
+    let tagged = own.rsplit_once('.').is_some_and(|(base, tag)| base == stem && !tag.is_empty());
+    if !(own == stem || own == primary || tagged || volume) {
+        return false;
+    }
+    match rule.primary.iter().position(|pattern| matches(pattern, &ext)) {
+        Some(own_rank) => own_rank > rank || volume,
+        None => true,
+    }
+}
+
+/// Groups files of the same folder under the first rule that finds a primary with
+/// at least one companion. Everything else is a group of one.
+pub fn group(files: Vec<FileInfo>, rules: &[CompanionRule]) -> Vec<FileGroup> {
+    let mut folders: BTreeMap<PathBuf, Vec<FileInfo>> = BTreeMap::new();
+    for file in files {
+        let dir = file.path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+        folders.entry(dir).or_default().push(file);
+    }
+
+    let mut groups = Vec::new();
+    for (_, mut files) in folders {
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        let names: Vec<String> = files.iter().map(|f| f.name.clone()).collect();
+        // Indexes by stem and by the stem's own stem, so large folders are not
+        // compared file against file
+        let mut by_stem: HashMap<&str, Vec<usize>> = HashMap::new();
+        let mut by_parent: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, name) in names.iter().enumerate() {
+            let own = stem(name);
+            by_stem.entry(own).or_default().push(i);
+            if let Some((parent, _)) = own.rsplit_once('.') {
+                by_parent.entry(parent).or_default().push(i);
+            }
+        }
+
+        let mut taken = vec![false; names.len()];
+        let mut found_groups: Vec<(usize, Vec<usize>)> = Vec::new();
+        for rule in rules {
+            for (rank, pattern) in rule.primary.iter().enumerate() {
+                for p in 0..names.len() {
+                    let name = names[p].as_str();
+                    if taken[p] || !matches(pattern, &extension(name)) {
+                        continue;
+                    }
+                    let own = stem(name);
+                    let lookups = [
+                        by_stem.get(own),
+                        by_stem.get(name),
+                        by_parent.get(own),
+                        volume_base(own).and_then(|base| by_parent.get(base)),
+                    ];
+                    let mut members: Vec<usize> = lookups
+                        .into_iter()
+                        .flatten()
+                        .flatten()
+                        .copied()
+                        .filter(|&i| i != p && !taken[i] && follows(rule, name, rank, &names[i]))
+                        .collect();
+                    if members.is_empty() {
+                        continue;
+                    }
+                    members.sort_unstable();
+                    members.dedup();
+                    taken[p] = true;
+                    for &i in &members {
+                        taken[i] = true;
+                    }
+                    found_groups.push((p, members));
+                }
+            }
+        }
+
+        let mut files: Vec<Option<FileInfo>> = files.into_iter().map(Some).collect();
+        for (primary, members) in found_groups {
+            let primary = files[primary].take().expect("grouped twice");
+            let companions = members.into_iter().filter_map(|i| files[i].take()).collect();
+            groups.push(FileGroup { primary, companions });
+        }
+        groups.extend(files.into_iter().flatten().map(|primary| FileGroup { primary, companions: Vec::new() }));
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(files: &[&str]) -> Vec<Vec<String>> {
+        let files = files.iter().map(|name| FileInfo::new(PathBuf::from("/photos").join(name))).collect();
+        let mut groups: Vec<Vec<String>> = group(files, &default_rules())
+            .into_iter()
+            .map(|g| std::iter::once(g.primary).chain(g.companions).map(|f| f.name).collect())
+            .collect();
+        groups.sort();
+        groups
+    }
+
+    #[test]
+    fn dotted_names_are_not_grouped_by_their_first_part() {
+        let groups = names(&["2024.01.05 party.jpg", "2024.01.06 beach.jpg", "2024.02.11 notes.jpg"]);
+        assert_eq!(groups.len(), 3);
+    }
+
+    #[test]
+    fn photos_of_one_kind_do_not_pull_each_other_in() {
+        assert_eq!(names(&["party.jpg", "party.1.jpg"]).len(), 2);
+        assert_eq!(names(&["clip.mp4", "clip.en.mp4"]).len(), 2);
+    }
+
+    #[test]
+    fn raw_keeps_its_jpeg_and_sidecars() {
+        let groups = names(&["IMG_001.CR2", "IMG_001.JPG", "IMG_001.CR2.xmp", "IMG_002.CR2"]);
+        assert_eq!(
+            groups,
+            vec![vec!["IMG_001.CR2", "IMG_001.CR2.xmp", "IMG_001.JPG"], vec!["IMG_002.CR2"]]
+        );
+    }
+
+    #[test]
+    fn tagged_subtitles_follow_their_video() {
+        let groups = names(&["2023.12.31 movie.mkv", "2023.12.31 movie.en.srt", "2023.12.31 movie.srt", "other.srt"]);
+        assert_eq!(
+            groups,
+            vec![
+                vec!["2023.12.31 movie.mkv", "2023.12.31 movie.en.srt", "2023.12.31 movie.srt"],
+                vec!["other.srt"],
+            ]
+        );
+    }
+
+    #[test]
+    fn split_archives_stay_together() {
+        let groups = names(&["backup.part1.rar", "backup.part2.rar", "backup.part3.rar", "other.part1.rar"]);
+        assert_eq!(
+            groups,
+            vec![vec!["backup.part1.rar", "backup.part2.rar", "backup.part3.rar"], vec!["other.part1.rar"]]
+        );
+        let groups = names(&["disk.7z.001", "disk.7z.002", "old.rar", "old.r00"]);
+        assert_eq!(groups, vec![vec!["disk.7z.001", "disk.7z.002"], vec!["old.rar", "old.r00"]]);
+    }
+
+    #[test]
+    fn shared_stem_is_swapped_on_rename() {
+        assert_eq!(shared_stem("2024.01.05 party.jpg", "2024.01.05 party.xmp"), "2024.01.05 party");
+        assert_eq!(shared_stem("IMG_001.CR2", "IMG_001.CR2.xmp"), "IMG_001");
+        assert_eq!(shared_stem("backup.part1.rar", "backup.part2.rar"), "backup");
+    }
+}
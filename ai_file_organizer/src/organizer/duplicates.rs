@@ -1,39 +1,614 @@
 use crate::models::FileInfo;
+use crate::organizer::cache::MetadataCache;
+use crate::organizer::hashing::{self, HashAlgorithm};
+use crate::organizer::metadata;
+use crate::organizer::transfer::{self, LinkKind};
+use crate::organizer::trash;
+use crate::organizer::undo::{self, UndoAction};
+use crate::organizer::utils::Progress;
+use anyhow::{Context, Result, bail};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering as CmpOrdering;
 use std::collections::HashMap;
-use anyhow::Result;
-use crate::organizer::metadata::calculate_hash;
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
 
-pub fn find_duplicates(files: &mut [FileInfo]) -> Result<HashMap<String, Vec<FileInfo>>> {
-    let mut hash_map: HashMap<String, Vec<FileInfo>> = HashMap::new();
+/// Bytes hashed from each end of a file in the partial stage
+const EDGE_BYTES: u64 = 4 * 1024;
+const COMPARE_BUFFER_SIZE: usize = 256 * 1024;
 
+/// Finds sets of identical files. Each stage only looks at what the previous one
+/// could not tell apart: equal sizes, then equal first and last few KB, then equal
+/// full hashes (from the cache where possible), and with `verify` equal bytes.
+/// Empty files are never reported.
+pub fn find_duplicates(
+    files: Vec<FileInfo>,
+    cache: &mut MetadataCache,
+    algorithm: HashAlgorithm,
+    verify: bool,
+) -> Result<HashMap<String, Vec<FileInfo>>> {
+    let stages = if verify { 4 } else { 3 };
+    let files = distinct_files(files);
+    let total = files.len();
+
+    let started = Instant::now();
+    let groups = regroup(vec![files], &format!("[1/{}] size", stages), |file| {
+        fs::metadata(&file.path).ok().map(|attr| attr.len()).filter(|&size| size > 0)
+    });
+    report(total, &groups, started);
+
+    let started = Instant::now();
+    let input = count(&groups);
+    let groups = regroup(groups, &format!("[2/{}] partial hash", stages), |file| {
+        hashing::hash_edges(&file.path, EDGE_BYTES)
+            .map_err(|e| log::warn!("Skipping {:?}: {}", file.path, e))
+            .ok()
+    });
+    report(input, &groups, started);
+
+    let started = Instant::now();
+    let input = count(&groups);
+    let groups = full_hashes(groups, cache, algorithm, &format!("[3/{}] full hash", stages));
+    report(input, &groups, started);
+
+    let groups = if verify {
+        let started = Instant::now();
+        let input = count(&groups);
+        let groups = compare_bytes(groups, &format!("[4/{}] byte comparison", stages));
+        report(input, &groups, started);
+        groups
+    } else {
+        groups
+    };
+
+    let mut duplicates = HashMap::new();
+    for mut set in groups {
+        set.sort_by(|a, b| a.path.cmp(&b.path));
+        let hash = set[0].metadata.hash.clone();
+        // Only a byte comparison can split a hash; keep such sets apart
+        let mut key = hash.clone();
+        let mut n = 1;
+        while duplicates.contains_key(&key) {
+            n += 1;
+            key = format!("{}-{}", hash, n);
+        }
+        duplicates.insert(key, set);
+    }
+    Ok(duplicates)
+}
+
+/// What a path resolves to on disk: device and inode on unix, the canonical path
+/// on Windows.
+#[cfg(unix)]
+type FileId = (u64, u64);
+#[cfg(windows)]
+type FileId = PathBuf;
+
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    let attr = fs::metadata(path).ok()?;
+    Some((attr.dev(), attr.ino()))
+}
+
+#[cfg(windows)]
+fn file_id(path: &Path) -> Option<FileId> {
+    fs::canonicalize(path).ok()
+}
+
+/// Keeps one path per file on disk. Hard links, symlinked folders and overlapping
+/// roots reach the same file under several names; deleting one name as a
+/// "duplicate" of another would delete the only copy. The real path wins over
+/// aliases through symlinks.
+pub(crate) fn distinct_files(files: Vec<FileInfo>) -> Vec<FileInfo> {
+    let mut by_id: HashMap<FileId, Vec<FileInfo>> = HashMap::new();
+    let mut unknown = Vec::new();
     for file in files {
-        if file.metadata.hash.is_empty() {
-            let _ = calculate_hash(file);
+        match file_id(&file.path) {
+            Some(id) => by_id.entry(id).or_default().push(file),
+            None => unknown.push(file),
+        }
+    }
+    let mut distinct = unknown;
+    for (_, mut aliases) in by_id {
+        aliases.sort_by(|a, b| a.path.cmp(&b.path));
+        let real = aliases
+            .iter()
+            .position(|file| fs::canonicalize(&file.path).is_ok_and(|real| real == file.path))
+            .unwrap_or(0);
+        let kept = aliases.swap_remove(real);
+        for alias in aliases {
+            log::debug!("{:?} is the same file as {:?}", alias.path, kept.path);
+        }
+        distinct.push(kept);
+    }
+    distinct
+}
+
+/// Splits every group by `key`, computed in parallel, and keeps the parts that
+/// still hold more than one file. Files without a key drop out.
+fn regroup<K, F>(groups: Vec<Vec<FileInfo>>, stage: &str, key: F) -> Vec<Vec<FileInfo>>
+where
+    K: Hash + Eq + Send,
+    F: Fn(&FileInfo) -> Option<K> + Sync,
+{
+    let progress = Progress::new(stage, count(&groups));
+    let groups = groups
+        .into_par_iter()
+        .flat_map_iter(|group| {
+            let mut parts: HashMap<K, Vec<FileInfo>> = HashMap::new();
+            for file in group {
+                let key = key(&file);
+                progress.tick();
+                if let Some(key) = key {
+                    parts.entry(key).or_default().push(file);
+                }
+            }
+            parts.into_values().filter(|part| part.len() > 1)
+        })
+        .collect();
+    progress.finish();
+    groups
+}
+
+/// Fills in full hashes, taking them from the cache when the file is unchanged and
+/// hashing the rest in parallel, then regroups by hash.
+fn full_hashes(
+    groups: Vec<Vec<FileInfo>>,
+    cache: &mut MetadataCache,
+    algorithm: HashAlgorithm,
+    stage: &str,
+) -> Vec<Vec<FileInfo>> {
+    let mut files: Vec<(FileInfo, bool)> = groups
+        .into_iter()
+        .flatten()
+        .map(|mut file| {
+            let cached = cache.lookup(&mut file).unwrap_or(false);
+            (file, cached)
+        })
+        .collect();
+
+    let progress = Progress::new(stage, files.len());
+    files.par_iter_mut().for_each(|(file, cached)| {
+        if !*cached {
+            let _ = metadata::extract_metadata(file);
+        }
+        if file.metadata.hash_algorithm != algorithm || file.metadata.hash.is_empty() {
+            if let Err(e) = metadata::calculate_hash(file, algorithm) {
+                log::warn!("Skipping {:?}: {}", file.path, e);
+                file.metadata.hash.clear();
+            }
+            *cached = false;
+        }
+        progress.tick();
+    });
+    progress.finish();
+
+    for (file, cached) in &files {
+        if !cached && !file.metadata.hash.is_empty() {
+            let _ = cache.insert(file);
+        }
+    }
+
+    let files = files.into_iter().map(|(file, _)| file).collect();
+    // Everything is hashed by now, so this regroup is just a lookup
+    regroup(vec![files], "", |file| Some(file.metadata.hash.clone()).filter(|hash| !hash.is_empty()))
+}
+
+/// Splits each group into runs of byte-identical files.
+fn compare_bytes(groups: Vec<Vec<FileInfo>>, stage: &str) -> Vec<Vec<FileInfo>> {
+    let progress = Progress::new(stage, count(&groups));
+    let groups = groups
+        .into_par_iter()
+        .flat_map_iter(|group| {
+            let mut runs: Vec<Vec<FileInfo>> = Vec::new();
+            for file in group {
+                let run = runs.iter_mut().find(|run| match same_content(&run[0].path, &file.path) {
+                    Ok(same) => same,
+                    Err(e) => {
+                        log::warn!("Could not compare {:?} and {:?}: {}", run[0].path, file.path, e);
+                        false
+                    }
+                });
+                match run {
+                    Some(run) => run.push(file),
+                    None => runs.push(vec![file]),
+                }
+                progress.tick();
+            }
+            runs.into_iter().filter(|run| run.len() > 1)
+        })
+        .collect();
+    progress.finish();
+    groups
+}
+
+fn same_content(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = BufReader::with_capacity(COMPARE_BUFFER_SIZE, File::open(a)?);
+    let mut b = BufReader::with_capacity(COMPARE_BUFFER_SIZE, File::open(b)?);
+    let mut left = vec![0u8; COMPARE_BUFFER_SIZE];
+    let mut right = vec![0u8; COMPARE_BUFFER_SIZE];
+    loop {
+        let n = fill(&mut a, &mut left)?;
+        if n != fill(&mut b, &mut right)? || left[..n] != right[..n] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
         }
-        
-        hash_map.entry(file.metadata.hash.clone())
-            .or_insert_with(Vec::new)
-            .push(file.clone());
     }
+}
 
-    // Keep only those with more than 1 occurrence
-    hash_map.retain(|_, v| v.len() > 1);
+/// Reads until `buffer` is full or the file ends.
+fn fill(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
 
-    Ok(hash_map)
+fn count(groups: &[Vec<FileInfo>]) -> usize {
+    groups.iter().map(Vec::len).sum()
 }
 
-pub fn remove_duplicates(duplicates: &HashMap<String, Vec<FileInfo>>, dry_run: bool) -> Result<()> {
-    for (hash, files) in duplicates {
-        println!("Duplicate set for hash {}:", hash);
-        // Skip the first one (keep it)
+pub(crate) fn report(input: usize, groups: &[Vec<FileInfo>], started: Instant) {
+    eprintln!(
+        "  {} file(s) in, {} candidate(s) in {} set(s) left ({:.2}s)",
+        input,
+        count(groups),
+        groups.len(),
+        started.elapsed().as_secs_f64()
+    );
+}
+
+/// Which file of a duplicate set survives `--remove`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeepPolicy {
+    /// The most recently modified copy
+    #[default]
+    Newest,
+    /// The least recently modified copy, usually the original
+    Oldest,
+    /// The copy with the shortest path, e.g. not buried in a backup folder
+    ShortestPath,
+    LongestPath,
+    /// The copy with the most extracted metadata (EXIF and the like)
+    RichestMetadata,
+    /// The picture with the most pixels, then the largest file; default for `--similar-images`
+    HighestResolution,
+}
+
+impl KeepPolicy {
+    fn label(self) -> &'static str {
+        match self {
+            KeepPolicy::Newest => "newest",
+            KeepPolicy::Oldest => "oldest",
+            KeepPolicy::ShortestPath => "shortest path",
+            KeepPolicy::LongestPath => "longest path",
+            KeepPolicy::RichestMetadata => "richest metadata",
+            KeepPolicy::HighestResolution => "highest resolution",
+        }
+    }
+
+    /// Orders `a` before `b` when `a` is the better file to keep.
+    fn compare(self, a: &FileInfo, b: &FileInfo) -> CmpOrdering {
+        let depth = |f: &FileInfo| (f.path.components().count(), f.path.as_os_str().len());
+        match self {
+            KeepPolicy::Newest => modified(b).cmp(&modified(a)),
+            KeepPolicy::Oldest => modified(a).cmp(&modified(b)),
+            KeepPolicy::ShortestPath => depth(a).cmp(&depth(b)),
+            KeepPolicy::LongestPath => depth(b).cmp(&depth(a)),
+            KeepPolicy::RichestMetadata => richness(b).cmp(&richness(a)),
+            KeepPolicy::HighestResolution => resolution(b).cmp(&resolution(a)),
+        }
+    }
+}
+
+fn modified(file: &FileInfo) -> Option<SystemTime> {
+    match file.metadata.modified {
+        Some(time) => Some(time.into()),
+        None => fs::metadata(&file.path).and_then(|attr| attr.modified()).ok(),
+    }
+}
+
+/// Pixels as recorded by the perceptual hashing, and the size in bytes.
+fn resolution(file: &FileInfo) -> (u64, u64) {
+    let dimension = |key: &str| file.metadata.extra.get(key).and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+    let size = fs::metadata(&file.path).map(|attr| attr.len()).unwrap_or(file.metadata.size);
+    (dimension("width") * dimension("height"), size)
+}
+
+fn richness(file: &FileInfo) -> usize {
+    let known = |value: &str| !value.is_empty() && value != "unknown";
+    file.metadata.extra.values().filter(|v| known(v)).count()
+        + usize::from(known(&file.metadata.mime_type))
+        + usize::from(file.metadata.created.is_some())
+}
+
+/// How `remove_duplicates` picks the file to keep in each set.
+#[derive(Debug, Clone, Default)]
+pub struct KeepRules {
+    pub policy: KeepPolicy,
+    /// Copies inside these folders are kept over any other
+    pub prefer: Vec<PathBuf>,
+    /// Copies inside these folders are never deleted
+    pub protect: Vec<PathBuf>,
+}
+
+impl KeepRules {
+    /// Resolves `prefer` and `protect` so they match however the scan spelled paths.
+    pub fn new(policy: KeepPolicy, prefer: Vec<PathBuf>, protect: Vec<PathBuf>) -> Result<Self> {
+        let resolve = |dirs: Vec<PathBuf>| -> Result<Vec<PathBuf>> {
+            dirs.into_iter()
+                .map(|dir| fs::canonicalize(&dir).with_context(|| format!("Cannot find folder {:?}", dir)))
+                .collect()
+        };
+        Ok(Self { policy, prefer: resolve(prefer)?, protect: resolve(protect)? })
+    }
+
+    pub(crate) fn is_protected(&self, file: &Path) -> bool {
+        within(file, &self.protect)
+    }
+
+    /// Sorts a set so the file to keep comes first: preferred copies, then protected
+    /// ones, then by policy, with the path as the tie breaker.
+    pub(crate) fn rank(&self, files: &mut [FileInfo]) {
+        files.sort_by(|a, b| {
+            within(&b.path, &self.prefer)
+                .cmp(&within(&a.path, &self.prefer))
+                .then_with(|| self.is_protected(&b.path).cmp(&self.is_protected(&a.path)))
+                .then_with(|| self.policy.compare(a, b))
+                .then_with(|| a.path.cmp(&b.path))
+        });
+    }
+}
+
+/// Keeps the sets pairing files of `library` with files outside it, i.e. incoming
+/// files the library already holds. Sets entirely on one side are dropped.
+pub fn known_to_library(
+    duplicates: HashMap<String, Vec<FileInfo>>,
+    library: &Path,
+) -> HashMap<String, Vec<FileInfo>> {
+    let library = [library.to_path_buf()];
+    duplicates
+        .into_iter()
+        .filter(|(_, set)| {
+            let in_library = set.iter().filter(|file| within(&file.path, &library)).count();
+            in_library > 0 && in_library < set.len()
+        })
+        .collect()
+}
+
+fn within(file: &Path, dirs: &[PathBuf]) -> bool {
+    if dirs.is_empty() {
+        return false;
+    }
+    let file = fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+    dirs.iter().any(|dir| file.starts_with(dir))
+}
+
+/// Deletes all but one file of every set, chosen by `rules`. Protected files stay
+/// even when they are not the one kept. Deleted files go to the trash and are
+/// recorded in the undo journal of `root`, unless `permanent` is set. The caller
+/// must hold the root lock.
+pub fn remove_duplicates(
+    root: &Path,
+    duplicates: &HashMap<String, Vec<FileInfo>>,
+    rules: &KeepRules,
+    permanent: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let mut history = undo::load_history(root)?;
+    let session_id = history.begin_session(std::env::args().collect::<Vec<_>>().join(" "));
+    for_each_redundant(duplicates, rules, |_, duplicate| {
+        match (dry_run, permanent) {
+            (true, true) => println!("  [DRY-RUN] Would permanently delete duplicate: {:?}", duplicate),
+            (true, false) => println!("  [DRY-RUN] Would move duplicate to trash: {:?}", duplicate),
+            (false, true) => {
+                println!("  Permanently deleting duplicate: {:?}", duplicate);
+                fs::remove_file(duplicate)?;
+            }
+            (false, false) => {
+                let location = trash::trash(duplicate)
+                    .with_context(|| format!("Could not trash {:?}; --permanent deletes instead", duplicate))?;
+                println!("  Moved duplicate to trash: {:?}", duplicate);
+                history.record(root, session_id, UndoAction::Trashed, duplicate.to_path_buf(), location, None)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Replaces all but one file of every set with a link to the one kept, recording
+/// each swap in the undo journal of `root`. The caller must hold the root lock.
+pub fn link_duplicates(
+    root: &Path,
+    duplicates: &HashMap<String, Vec<FileInfo>>,
+    rules: &KeepRules,
+    kind: LinkKind,
+    dry_run: bool,
+) -> Result<()> {
+    let mut history = undo::load_history(root)?;
+    let session_id = history.begin_session(std::env::args().collect::<Vec<_>>().join(" "));
+    let mut failed = 0;
+    for_each_redundant(duplicates, rules, |keep, duplicate| {
+        if dry_run {
+            println!("  [DRY-RUN] Would replace with {}: {:?}", kind.verb(), duplicate);
+            return Ok(());
+        }
+        match transfer::replace_with_link(kind, keep, duplicate) {
+            Ok(()) => {
+                println!("  Replaced with {}: {:?}", kind.verb(), duplicate);
+                let (original, kept) = (duplicate.to_path_buf(), keep.to_path_buf());
+                history.record(root, session_id, UndoAction::LinkedDuplicate(kind), original, kept, None)?;
+            }
+            Err(e) => {
+                eprintln!("  Left {:?} as it was: {:#}", duplicate, e);
+                failed += 1;
+            }
+        }
+        Ok(())
+    })?;
+    if failed > 0 {
+        bail!("{} duplicate(s) could not be replaced with a {}", failed, kind.verb());
+    }
+    Ok(())
+}
+
+/// Ranks every set, prints which copy it keeps, and calls `handle(kept, duplicate)`
+/// for each copy that may go. Protected copies are listed but never handed out.
+fn for_each_redundant(
+    duplicates: &HashMap<String, Vec<FileInfo>>,
+    rules: &KeepRules,
+    mut handle: impl FnMut(&Path, &Path) -> Result<()>,
+) -> Result<()> {
+    let mut sets: Vec<_> = duplicates.iter().collect();
+    sets.sort_by(|a, b| a.0.cmp(b.0));
+    for (hash, files) in sets {
+        let mut files = files.clone();
+        rules.rank(&mut files);
+        println!("Duplicate set for hash {} (keeping {}):", hash, rules.policy.label());
+        println!("  Keeping: {:?}", files[0].path);
         for duplicate in files.iter().skip(1) {
-            if dry_run {
-                println!("  [DRY-RUN] Would remove duplicate: {:?}", duplicate.path);
+            // Never hand out a second name of the kept file itself
+            if file_id(&duplicate.path).is_some_and(|id| file_id(&files[0].path) == Some(id)) {
+                println!("  Same file as the one kept, skipping: {:?}", duplicate.path);
+            } else if rules.is_protected(&duplicate.path) {
+                println!("  Protected, keeping: {:?}", duplicate.path);
             } else {
-                println!("  Removing duplicate: {:?}", duplicate.path);
-                std::fs::remove_file(&duplicate.path)?;
+                handle(&files[0].path, &duplicate.path)?;
             }
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(dir: &Path) -> Vec<FileInfo> {
+        let mut files: Vec<_> = fs::read_dir(dir).unwrap().map(|entry| FileInfo::new(entry.unwrap().path())).collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    }
+
+    fn names(set: &[FileInfo]) -> Vec<String> {
+        set.iter().map(|file| file.name.clone()).collect()
+    }
+
+    #[test]
+    fn only_identical_content_survives_every_stage() {
+        let tmp = tempfile::tempdir().unwrap();
+        let content = vec![b'x'; 3 * EDGE_BYTES as usize];
+        // Same size throughout; `middle` only differs where the partial hash does not look
+        let mut middle = content.clone();
+        middle[content.len() / 2] = b'y';
+        let mut edge = content.clone();
+        edge[0] = b'y';
+        fs::write(tmp.path().join("a.bin"), &content).unwrap();
+        fs::write(tmp.path().join("b.bin"), &content).unwrap();
+        fs::write(tmp.path().join("middle.bin"), &middle).unwrap();
+        fs::write(tmp.path().join("edge.bin"), &edge).unwrap();
+        fs::write(tmp.path().join("empty1"), "").unwrap();
+        fs::write(tmp.path().join("empty2"), "").unwrap();
+
+        for verify in [false, true] {
+            let mut cache = MetadataCache::open(false);
+            let duplicates = find_duplicates(scan(tmp.path()), &mut cache, HashAlgorithm::Blake3, verify).unwrap();
+            let sets: Vec<_> = duplicates.values().map(|set| names(set)).collect();
+            assert_eq!(sets, vec![vec!["a.bin".to_string(), "b.bin".to_string()]], "verify = {}", verify);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_links_count_as_one_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("a.txt"), "same").unwrap();
+        fs::hard_link(tmp.path().join("a.txt"), tmp.path().join("a-link.txt")).unwrap();
+        assert_eq!(distinct_files(scan(tmp.path())).len(), 1);
+
+        let mut cache = MetadataCache::open(false);
+        let duplicates = find_duplicates(scan(tmp.path()), &mut cache, HashAlgorithm::Blake3, true).unwrap();
+        assert!(duplicates.is_empty());
+
+        fs::write(tmp.path().join("copy.txt"), "same").unwrap();
+        let duplicates = find_duplicates(scan(tmp.path()), &mut cache, HashAlgorithm::Blake3, true).unwrap();
+        let set = duplicates.values().next().unwrap();
+        assert_eq!(set.len(), 2);
+        assert!(set.iter().any(|file| file.name == "copy.txt"));
+    }
+
+    fn dated(path: &str, day: u32) -> FileInfo {
+        use chrono::{Local, TimeZone};
+        let mut file = FileInfo::new(PathBuf::from(path));
+        file.metadata.modified = Some(Local.with_ymd_and_hms(2024, 1, day, 12, 0, 0).unwrap());
+        file
+    }
+
+    fn kept(rules: &KeepRules, mut files: Vec<FileInfo>) -> PathBuf {
+        rules.rank(&mut files);
+        files.swap_remove(0).path
+    }
+
+    #[test]
+    fn keep_policies_pick_the_expected_copy() {
+        let set = vec![dated("/p/backup/old/a.jpg", 1), dated("/p/a.jpg", 2), dated("/p/backup/a.jpg", 3)];
+        let keep = |policy| kept(&KeepRules { policy, ..KeepRules::default() }, set.clone());
+        assert_eq!(keep(KeepPolicy::Newest), Path::new("/p/backup/a.jpg"));
+        assert_eq!(keep(KeepPolicy::Oldest), Path::new("/p/backup/old/a.jpg"));
+        assert_eq!(keep(KeepPolicy::ShortestPath), Path::new("/p/a.jpg"));
+        assert_eq!(keep(KeepPolicy::LongestPath), Path::new("/p/backup/old/a.jpg"));
+
+        // Ties fall back to the path so every run keeps the same copy
+        let tied = vec![dated("/p/b.jpg", 1), dated("/p/a.jpg", 1)];
+        assert_eq!(kept(&KeepRules::default(), tied), Path::new("/p/a.jpg"));
+    }
+
+    #[test]
+    fn preferred_then_protected_copies_outrank_the_policy() {
+        let set = vec![dated("/p/new.jpg", 3), dated("/p/safe/a.jpg", 2), dated("/p/keep/a.jpg", 1)];
+        let protect = vec![PathBuf::from("/p/safe")];
+        let rules = KeepRules { protect: protect.clone(), ..KeepRules::default() };
+        assert_eq!(kept(&rules, set.clone()), Path::new("/p/safe/a.jpg"));
+
+        let rules = KeepRules { prefer: vec![PathBuf::from("/p/keep")], protect, ..KeepRules::default() };
+        assert_eq!(kept(&rules, set), Path::new("/p/keep/a.jpg"));
+    }
+
+    #[test]
+    fn protected_copies_are_never_removed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(tmp.path()).unwrap();
+        for dir in ["keep", "safe", "other"] {
+            fs::create_dir(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("a.txt"), "same").unwrap();
+        }
+        let set: Vec<_> = ["keep", "safe", "other"].iter().map(|dir| FileInfo::new(root.join(dir).join("a.txt"))).collect();
+        let duplicates = HashMap::from([("hash".to_string(), set)]);
+        let rules = KeepRules::new(KeepPolicy::Newest, vec![root.join("keep")], vec![root.join("safe")]).unwrap();
+
+        let mut handed = Vec::new();
+        for_each_redundant(&duplicates, &rules, |keep, duplicate| {
+            assert_eq!(keep, root.join("keep/a.txt"));
+            handed.push(duplicate.to_path_buf());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(handed, vec![root.join("other/a.txt")]);
+
+        remove_duplicates(&root, &duplicates, &rules, true, false).unwrap();
+        assert!(root.join("keep/a.txt").exists() && root.join("safe/a.txt").exists());
+        assert!(!root.join("other/a.txt").exists());
+    }
+}
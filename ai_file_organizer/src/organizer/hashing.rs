@@ -0,0 +1,81 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use xxhash_rust::xxh3::Xxh3;
+
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+/// From this size on blake3 memory-maps the file and hashes it on all cores
+const MMAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// Content hash used to tell files apart. Hashes of different algorithms are never
+/// comparable, so the algorithm is stored next to every hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashAlgorithm {
+    /// Cryptographic and fast; multithreaded on large files
+    #[default]
+    Blake3,
+    /// For matching checksums published elsewhere
+    Sha256,
+    /// Not collision resistant; only for prefiltering candidates
+    Xxh3,
+}
+
+/// Hashes the file at `path` in bounded memory and returns the lowercase hex digest.
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> Result<String> {
+    match algorithm {
+        HashAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            if path.metadata()?.len() >= MMAP_THRESHOLD {
+                hasher.update_mmap_rayon(path)?;
+            } else {
+                stream(path, |chunk| {
+                    hasher.update(chunk);
+                })?;
+            }
+            Ok(hasher.finalize().to_hex().to_string())
+        }
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            stream(path, |chunk| hasher.update(chunk))?;
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+        HashAlgorithm::Xxh3 => {
+            let mut hasher = Xxh3::new();
+            stream(path, |chunk| hasher.update(chunk))?;
+            Ok(format!("{:016x}", hasher.digest()))
+        }
+    }
+}
+
+/// xxh3 of the first and last `edge` bytes, a cheap first look at files of equal size.
+pub fn hash_edges(path: &Path, edge: u64) -> Result<u64> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hasher = Xxh3::new();
+    let mut buffer = Vec::new();
+    file.by_ref().take(edge).read_to_end(&mut buffer)?;
+    hasher.update(&buffer);
+    if size > edge {
+        buffer.clear();
+        file.seek(SeekFrom::Start(size.saturating_sub(edge).max(edge)))?;
+        file.take(edge).read_to_end(&mut buffer)?;
+        hasher.update(&buffer);
+    }
+    Ok(hasher.digest())
+}
+
+fn stream(path: &Path, mut update: impl FnMut(&[u8])) -> Result<()> {
+    let mut reader = BufReader::with_capacity(READ_BUFFER_SIZE, File::open(path)?);
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(());
+        }
+        update(&buffer[..n]);
+    }
+}
@@ -0,0 +1,31 @@
+use crate::constants::LOCK_FILE;
+use anyhow::{Result, bail};
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Advisory lock on a root directory, held for as long as the value lives.
+/// The OS drops the lock if the process dies, so a crash never leaves it stuck.
+pub struct RootLock {
+    file: File,
+}
+
+impl RootLock {
+    pub fn acquire(root: &Path) -> Result<Self> {
+        let path = root.join(LOCK_FILE);
+        let mut file = OpenOptions::new().create(true).truncate(false).write(true).open(&path)?;
+        if file.try_lock_exclusive().is_err() {
+            bail!("{:?} is being organized by another process (lock held on {:?})", root, path);
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for RootLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
@@ -1,7 +1,12 @@
 use crate::models::FileInfo;
+use crate::organizer::hashing::{self, HashAlgorithm};
+use std::collections::HashMap;
 use std::fs;
-use anyhow::Result;
-use chrono::DateTime;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, FixedOffset, NaiveDateTime};
 
 pub fn extract_metadata(file: &mut FileInfo) -> Result<()> {
     let path = &file.path;
@@ -48,16 +53,271 @@ fn extract_image_metadata(file: &mut FileInfo) -> Result<()> {
     Ok(())
 }
 
-fn extract_pdf_metadata(_file: &mut FileInfo) -> Result<()> {
-    // Basic PDF metadata extraction (simplified for MVP)
-    // In a real app, use pdf-extract crate
+/// Start of the extracted text kept in `extra["text"]`, for rules and the AI prompt
+const PDF_TEXT_PREVIEW: usize = 4 * 1024;
+
+/// Info dictionary entries copied into `extra`, besides the creation date
+const PDF_INFO_FIELDS: [(&[u8], &str); 5] = [
+    (b"Title", "title"),
+    (b"Author", "author"),
+    (b"Subject", "subject"),
+    (b"Keywords", "keywords"),
+    (b"Creator", "creator"),
+];
+
+/// Fills `extra` with the Info dictionary, the page count and the start of the text.
+/// Encrypted or malformed documents keep whatever could be read and record why in
+/// `pdf_error`, so the rest of the metadata survives.
+fn extract_pdf_metadata(file: &mut FileInfo) -> Result<()> {
+    let path = file.path.clone();
+    let extra = &mut file.metadata.extra;
+    // pdf-extract panics on some malformed documents
+    let error = match panic::catch_unwind(AssertUnwindSafe(|| read_pdf(&path, extra))) {
+        Ok(Ok(())) => return Ok(()),
+        Ok(Err(e)) => format!("{:#}", e),
+        Err(_) => "PDF parser crashed".to_string(),
+    };
+    log::warn!("Incomplete PDF metadata for {:?}: {}", path, error);
+    extra.insert("pdf_error".to_string(), error);
+    Ok(())
+}
+
+fn read_pdf(path: &Path, extra: &mut HashMap<String, String>) -> Result<()> {
+    let mut doc = pdf_extract::Document::load(path)?;
+    extra.insert("pages".to_string(), doc.get_pages().len().to_string());
+    decrypt_pdf(&mut doc)?;
+
+    let info = doc.trailer.get(b"Info").ok().and_then(|info| doc.dereference(info).ok());
+    if let Some(info) = info.and_then(|(_, info)| info.as_dict().ok()) {
+        let field = |key: &[u8]| {
+            let value = doc.dereference(info.get(key).ok()?).ok()?.1.as_str().ok()?;
+            Some(pdf_string(value).trim().to_string()).filter(|v| !v.is_empty())
+        };
+        for (key, name) in PDF_INFO_FIELDS {
+            if let Some(value) = field(key) {
+                extra.insert(name.to_string(), value);
+            }
+        }
+        if let Some(date) = field(b"CreationDate") {
+            extra.insert("creation_date".to_string(), pdf_date(&date));
+        }
+    }
+
+    let text = pdf_text(&doc, PDF_TEXT_PREVIEW)?;
+    let mut text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.len() > PDF_TEXT_PREVIEW {
+        let end = (0..=PDF_TEXT_PREVIEW).rev().find(|&i| text.is_char_boundary(i)).unwrap_or(0);
+        text.truncate(end);
+    }
+    if !text.is_empty() {
+        extra.insert("text".to_string(), text);
+    }
     Ok(())
 }
 
-pub fn calculate_hash(file: &mut FileInfo) -> Result<()> {
-    let mut hasher = blake3::Hasher::new();
-    let bytes = fs::read(&file.path)?;
-    hasher.update(&bytes);
-    file.metadata.hash = hasher.finalize().to_string();
+/// Opens documents that are only protected against editing; those needing a
+/// password to read stay encrypted and fail.
+fn decrypt_pdf(doc: &mut pdf_extract::Document) -> Result<()> {
+    if doc.is_encrypted() && doc.decrypt("").is_err() {
+        bail!("encrypted; needs a password");
+    }
     Ok(())
 }
+
+/// Text of the pages in order, stopping once at least `limit` bytes are read.
+fn pdf_text(doc: &pdf_extract::Document, limit: usize) -> Result<String> {
+    let mut text = String::new();
+    for page in doc.get_pages().into_keys() {
+        if text.len() >= limit {
+            break;
+        }
+        let mut page_text = String::new();
+        pdf_extract::output_doc_page(doc, &mut pdf_extract::PlainTextOutput::new(&mut page_text), page)?;
+        text.push_str(&page_text);
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+/// Decodes a PDF text string: UTF-16BE or UTF-8 after a byte order mark, otherwise
+/// PDFDocEncoding, which is Latin-1 for all practical purposes.
+fn pdf_string(bytes: &[u8]) -> String {
+    if let Some(utf16) = bytes.strip_prefix(&[0xfe, 0xff]) {
+        let units = utf16.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+        char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+    } else if let Some(utf8) = bytes.strip_prefix(&[0xef, 0xbb, 0xbf]) {
+        String::from_utf8_lossy(utf8).into_owned()
+    } else {
+        bytes.iter().map(|&b| char::from(b)).collect()
+    }
+}
+
+/// Turns a PDF date like `D:20240131120000+01'00'` into RFC 3339, or into
+/// `2024-01-31 12:00:00` without a time zone. Missing parts default to the start of
+/// the period; unreadable dates are kept as they are.
+fn pdf_date(raw: &str) -> String {
+    let value = raw.strip_prefix("D:").unwrap_or(raw);
+    let digits = value.bytes().take_while(u8::is_ascii_digit).count().min(14);
+    // Every part after the year has two digits; a dangling one is dropped
+    let digits = digits - digits % 2;
+    if digits < 4 {
+        return raw.to_string();
+    }
+    let padded = format!("{}{}", &value[..digits], &"0101000000"[digits - 4..]);
+    let Ok(date) = NaiveDateTime::parse_from_str(&padded, "%Y%m%d%H%M%S") else {
+        return raw.to_string();
+    };
+    let zone = value[digits..].trim_start_matches(|c: char| c.is_ascii_digit());
+    let offset: Vec<i32> = zone
+        .get(1..)
+        .unwrap_or("")
+        .split('\'')
+        .filter_map(|part| part.parse().ok())
+        .collect();
+    let sign = match zone.chars().next() {
+        Some('Z') => return date.and_utc().to_rfc3339(),
+        Some('+') => 1,
+        Some('-') => -1,
+        _ => return date.format("%Y-%m-%d %H:%M:%S").to_string(),
+    };
+    let seconds = sign * (offset.first().unwrap_or(&0) * 3600 + offset.get(1).unwrap_or(&0) * 60);
+    match FixedOffset::east_opt(seconds).and_then(|zone| date.and_local_timezone(zone).single()) {
+        Some(date) => date.to_rfc3339(),
+        None => date.format("%Y-%m-%d %H:%M:%S").to_string(),
+    }
+}
+
+/// Plain text read at most this far; enough to recognise a document
+const MAX_TEXT_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Whether `extract_text` understands files with this extension.
+pub fn has_text(extension: &str) -> bool {
+    matches!(
+        extension,
+        "txt" | "text" | "md" | "markdown" | "rst" | "org" | "tex" | "csv" | "tsv" | "log" | "html" | "htm"
+            | "pdf" | "docx" | "pptx" | "xlsx" | "odt" | "odp" | "ods"
+    )
+}
+
+/// The readable text of a plain text, PDF or office file; None for other formats.
+pub fn extract_text(file: &FileInfo) -> Result<Option<String>> {
+    let path = &file.path;
+    let text = match file.metadata.extension.as_str() {
+        "html" | "htm" => strip_markup(&read_text(path)?),
+        "pdf" => {
+            // pdf-extract panics on some malformed documents
+            panic::catch_unwind(|| -> Result<String> {
+                let mut doc = pdf_extract::Document::load(path)?;
+                decrypt_pdf(&mut doc)?;
+                pdf_text(&doc, usize::MAX)
+            })
+            .map_err(|_| anyhow!("PDF parser crashed"))??
+        }
+        "docx" => office_text(path, |name| name == "word/document.xml")?,
+        "pptx" => office_text(path, |name| name.starts_with("ppt/slides/slide") && name.ends_with(".xml"))?,
+        "xlsx" => office_text(path, |name| name == "xl/sharedStrings.xml")?,
+        "odt" | "odp" | "ods" => office_text(path, |name| name == "content.xml")?,
+        ext if has_text(ext) => read_text(path)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(text))
+}
+
+fn read_text(path: &Path) -> Result<String> {
+    let mut bytes = Vec::new();
+    fs::File::open(path)?.take(MAX_TEXT_BYTES).read_to_end(&mut bytes)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Concatenates the text of the XML parts of an office document (a zip archive).
+fn office_text(path: &Path, wanted: impl Fn(&str) -> bool) -> Result<String> {
+    let mut archive = zip::ZipArchive::new(fs::File::open(path)?)?;
+    let mut names: Vec<String> = archive.file_names().filter(|name| wanted(name)).map(String::from).collect();
+    // slide10.xml after slide9.xml
+    names.sort_by_key(|name| (name.len(), name.clone()));
+    let mut text = String::new();
+    for name in names {
+        let mut xml = String::new();
+        archive.by_name(&name)?.take(MAX_TEXT_BYTES).read_to_string(&mut xml)?;
+        text.push_str(&strip_markup(&xml));
+        text.push(' ');
+    }
+    Ok(text)
+}
+
+/// Drops XML/HTML tags and decodes the common entities. Paragraph, cell and
+/// break tags become spaces; others vanish, as office formats split words
+/// across runs.
+fn strip_markup(markup: &str) -> String {
+    let mut text = String::with_capacity(markup.len() / 2);
+    let mut rest = markup;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = rest[start + 1..start + end].trim_start_matches('/');
+        let name = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or("");
+        let local = name.rsplit(':').next().unwrap_or(name).to_ascii_lowercase();
+        if matches!(
+            local.as_str(),
+            "p" | "br" | "tab" | "h" | "si" | "c" | "td" | "th" | "li" | "div" | "tr" | "table-cell" | "line-break"
+        ) {
+            text.push(' ');
+        }
+        rest = &rest[start + end + 1..];
+    }
+    text.push_str(rest);
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+pub fn calculate_hash(file: &mut FileInfo, algorithm: HashAlgorithm) -> Result<()> {
+    file.metadata.hash = hashing::hash_file(&file.path, algorithm)?;
+    file.metadata.hash_algorithm = algorithm;
+    Ok(())
+}
+
+/// The blake3 hash used for undo checks and collision handling.
+pub fn hash_path(path: &Path) -> Result<String> {
+    hashing::hash_file(path, HashAlgorithm::Blake3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_pdf_strings() {
+        assert_eq!(pdf_string(b"Annual report"), "Annual report");
+        assert_eq!(pdf_string(b"caf\xe9"), "café");
+        assert_eq!(pdf_string(b"\xef\xbb\xbfna\xc3\xafve"), "naïve");
+        assert_eq!(pdf_string(&[0xfe, 0xff, 0x00, 0x48, 0x00, 0xe9, 0xd8, 0x3d, 0xde, 0x00]), "Hé😀");
+        // A lone surrogate or a dangling byte must not fail the whole string
+        assert_eq!(pdf_string(&[0xfe, 0xff, 0xd8, 0x3d, 0x00, 0x41, 0x00]), "\u{fffd}A");
+    }
+
+    #[test]
+    fn converts_pdf_dates() {
+        assert_eq!(pdf_date("D:20240131120000+01'00'"), "2024-01-31T12:00:00+01:00");
+        assert_eq!(pdf_date("D:20240131120000-05'30"), "2024-01-31T12:00:00-05:30");
+        assert_eq!(pdf_date("D:20240131120000Z"), "2024-01-31T12:00:00+00:00");
+        assert_eq!(pdf_date("D:20240131120000"), "2024-01-31 12:00:00");
+        assert_eq!(pdf_date("20240131"), "2024-01-31 00:00:00");
+        assert_eq!(pdf_date("D:2024"), "2024-01-01 00:00:00");
+        assert_eq!(pdf_date("D:20241"), "2024-01-01 00:00:00");
+    }
+
+    #[test]
+    fn keeps_unreadable_pdf_dates() {
+        assert_eq!(pdf_date("yesterday"), "yesterday");
+        assert_eq!(pdf_date("D:202"), "D:202");
+        assert_eq!(pdf_date("D:20241399"), "D:20241399");
+        assert_eq!(pdf_date("D:20240131120000+99'00'"), "2024-01-31 12:00:00");
+    }
+}
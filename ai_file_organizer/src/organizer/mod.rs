@@ -1,37 +1,144 @@
 pub mod archives;
+pub mod cache;
+pub mod companions;
 pub mod scanner;
+pub mod similar;
 pub mod renamer;
+pub mod report;
 pub mod classifier;
 pub mod rules;
 pub mod ai;
 pub mod duplicates;
+pub mod hashing;
 pub mod metadata;
 pub mod undo;
 pub mod utils;
+pub mod wal;
+pub mod plan;
+pub mod template;
+pub mod transfer;
+pub mod trash;
+pub mod lock;
 pub mod watcher;
 
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use anyhow::Result;
 
 pub struct Organizer {
     pub dry_run: bool,
     pub use_ai: bool,
     pub custom_rules: Option<String>,
+    pub plan_out: Option<PathBuf>,
+    pub output_pattern: String,
+    pub ai_rename: bool,
+    pub collision_policy: renamer::CollisionPolicy,
+    pub mode: renamer::PlacementMode,
+    /// OpenAI key from the configuration; `OPENAI_API_KEY` is used when unset
+    pub api_key: Option<String>,
+    pub scan_options: scanner::ScanOptions,
+    pub atomic_dirs: scanner::AtomicPolicy,
+    pub companion_rules: Vec<companions::CompanionRule>,
+    /// Reuse metadata of unchanged files from earlier runs
+    pub use_cache: bool,
 }
 
 impl Organizer {
-    pub fn new(dry_run: bool, use_ai: bool, custom_rules: Option<String>) -> Self {
-        Self { dry_run, use_ai, custom_rules }
+    /// Starts from the merged configuration; `with_*` calls override single settings.
+    pub fn new(config: &crate::config::AppConfig, dry_run: bool, use_ai: bool, custom_rules: Option<String>) -> Self {
+        let config = config.clone();
+        Self {
+            dry_run,
+            use_ai,
+            custom_rules,
+            plan_out: None,
+            output_pattern: config.default_output_pattern,
+            ai_rename: false,
+            collision_policy: renamer::CollisionPolicy::default(),
+            mode: renamer::PlacementMode::default(),
+            api_key: config.openai_api_key,
+            scan_options: scanner::ScanOptions {
+                ignore_list: config.ignore_list,
+                atomic_markers: config.atomic_markers,
+                ..Default::default()
+            },
+            atomic_dirs: config.atomic_dirs,
+            companion_rules: config.companion_rules,
+            use_cache: true,
+        }
+    }
+
+    /// Write the plan to `path` instead of applying it.
+    pub fn with_plan_out(mut self, path: Option<PathBuf>) -> Self {
+        self.plan_out = path;
+        self
+    }
+
+    /// Let the AI propose file names for files no rule renames.
+    pub fn with_ai_rename(mut self, ai_rename: bool) -> Self {
+        self.ai_rename = ai_rename;
+        self
+    }
+
+    pub fn with_collision_policy(mut self, policy: renamer::CollisionPolicy) -> Self {
+        self.collision_policy = policy;
+        self
+    }
+
+    /// Copy or link files into the organized tree instead of moving them.
+    pub fn with_mode(mut self, mode: renamer::PlacementMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_scan_options(mut self, options: scanner::ScanOptions) -> Self {
+        self.scan_options = options;
+        self
+    }
+
+    /// Extract every file afresh instead of consulting the metadata cache.
+    pub fn with_cache(mut self, use_cache: bool) -> Self {
+        self.use_cache = use_cache;
+        self
     }
 
     pub async fn process(&self, path: &Path) -> Result<()> {
         log::info!("Starting organization of {:?}", path);
-        
+
+        // Undo entries store absolute paths so they survive a change of cwd
+        let root = std::fs::canonicalize(path)?;
+        let path = root.as_path();
+
+        let writes = !self.dry_run && self.plan_out.is_none();
+        let _lock = if writes { Some(lock_and_recover(path)?) } else { None };
+
+        let plan = self.build_plan(path).await?;
+
+        if let Some(out) = &self.plan_out {
+            plan.save(out)?;
+            println!("Wrote plan with {} operation(s) to {:?}", plan.operations.len(), out);
+        } else if self.dry_run {
+            plan.print();
+        } else {
+            plan::apply(&plan)?;
+        }
+
+        Ok(())
+    }
+
+    /// Scans and classifies `path` into a reviewable plan without moving anything.
+    pub async fn build_plan(&self, path: &Path) -> Result<plan::Plan> {
         // Step 1: Scan
-        let mut files = scanner::scan(path)?;
-        log::info!("Found {} files", files.len());
+        let options = scanner::ScanOptions { atomic_units: true, ..self.scan_options.clone() };
+        let (files, atomic) = scanner::scan_units(path, &options)?;
+        log::info!("Found {} files and {} atomic directories", files.len(), atomic.len());
 
-        let renamer = renamer::Renamer::new(path.to_path_buf(), self.dry_run);
+        let renamer = renamer::Renamer::new(
+            path.to_path_buf(),
+            template::Template::parse(&self.output_pattern)?,
+            self.collision_policy,
+            self.mode,
+        );
         
         // Load rules if any
         let rules_path = if let Some(custom) = &self.custom_rules {
@@ -48,45 +155,123 @@ impl Organizer {
 
         // Initialize AI if needed
         let ai_classifier = if self.use_ai {
-            // Get API key from env for now
-            if let Ok(key) = std::env::var("OPENAI_API_KEY") {
-                Some(ai::AIClassifier::new(key))
-            } else {
-                log::warn!("AI enabled but OPENAI_API_KEY not set");
-                None
+            match self.api_key.clone().or_else(|| std::env::var("OPENAI_API_KEY").ok()) {
+                Some(key) => Some(ai::AIClassifier::new(key)),
+                None => {
+                    log::warn!("AI enabled but no openai_api_key configured and OPENAI_API_KEY not set");
+                    None
+                }
             }
         } else {
             None
         };
 
-        for file in &mut files {
+        let classifier = classifier::Classifier::from_parts(rules_config, ai_classifier);
+        let mut plan = plan::Plan::new(path.to_path_buf());
+        let mut reserved = HashMap::new();
+        let mut cache = cache::MetadataCache::open(self.use_cache);
+
+        // Sidecars ride along with their primary file and are never classified alone
+        for mut group in companions::group(files, &self.companion_rules) {
+            let file = &mut group.primary;
             // Step 2: Extract Metadata
-            let _ = metadata::extract_metadata(file);
+            let _ = cache.extract(file);
 
             // Step 3: Classify
-            let mut category = "Unknown".to_string();
-
-            // Try rules first
-            if let Some(config) = &rules_config {
-                if let Some(cat) = rules::classify_by_rules(file, config) {
-                    category = cat;
+            let mut classification = classifier::Classification {
+                category: "Unknown".to_string(),
+                reason: "no rule matched".to_string(),
+                output_pattern: None,
+                rename_to: None,
+                mode: None,
+            };
+            if let Some(classifier) = &classifier {
+                match classifier.classify(file).await {
+                    Ok(Some(c)) => classification = c,
+                    Ok(None) => {}
+                    Err(e) => log::warn!("Classification of {:?} failed: {}", file.path, e),
                 }
             }
 
-            // Try AI if rules didn't work and AI is available
-            if category == "Unknown" {
-                if let Some(ai) = &ai_classifier {
-                    if let Ok(ai_cat) = ai.classify(file).await {
-                        category = ai_cat;
+            if self.ai_rename && classification.rename_to.is_none() {
+                if let Some(ai) = classifier.as_ref().and_then(|c| c.ai()) {
+                    match ai.suggest_name(file).await {
+                        Ok(name) => file.suggested_name = Some(name),
+                        Err(e) => log::warn!("Name suggestion for {:?} failed: {}", file.path, e),
                     }
                 }
             }
 
-            // Step 4: Rename/Move
-            let dest = renamer.apply(file, &category)?;
-            println!("Processed {:?} -> {:?}", file.path, dest);
+            // Step 4: Plan the Rename/Move
+            let mut primary = renamer.plan_move(file, classification, &mut reserved)?;
+            if !group.companions.is_empty() {
+                primary.group = Some(primary.source.clone());
+            }
+            let mut companion_ops = Vec::new();
+            for companion in &mut group.companions {
+                let _ = cache.extract(companion);
+                companion_ops.push(renamer.plan_companion(companion, &primary, &mut reserved)?);
+            }
+            plan.operations.push(primary);
+            plan.operations.extend(companion_ops);
         }
 
-        Ok(())
+        cache.save()?;
+
+        // Step 5: Atomic directories go as one unit, or not at all
+        for dir in &atomic {
+            plan.operations.push(renamer.plan_directory(dir, self.atomic_dirs, &mut reserved)?);
+        }
+
+        Ok(plan)
+    }
+}
+
+/// Takes the root lock and finishes any run a crash left behind, so the caller starts
+/// from a consistent tree.
+pub fn lock_and_recover(root: &Path) -> Result<lock::RootLock> {
+    let lock = lock::RootLock::acquire(root)?;
+    if wal::pending(root) {
+        println!("Finishing interrupted run in {:?}", root);
+        wal::recover(root, wal::RecoverAction::Finish)?;
+    }
+    Ok(lock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::constants::PROJECTS_CATEGORY;
+    use std::fs;
+
+    #[tokio::test]
+    async fn atomic_directory_moves_whole_and_undoes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(tmp.path()).unwrap();
+        fs::create_dir_all(root.join("proj/.git")).unwrap();
+        fs::write(root.join("proj/.git/HEAD"), "ref: refs/heads/main\n").unwrap();
+        fs::write(root.join("proj/a.txt"), "project notes").unwrap();
+
+        let config = AppConfig { atomic_dirs: scanner::AtomicPolicy::Move, ..AppConfig::default() };
+        let organizer = Organizer::new(&config, false, false, None).with_cache(false);
+        let plan = organizer.build_plan(&root).await.unwrap();
+        let unit = plan.operations.iter().find(|op| op.source == root.join("proj")).expect("directory is planned");
+        assert_eq!(unit.action, plan::PlanAction::Move);
+        assert_eq!(unit.size, "ref: refs/heads/main\n".len() as u64 + "project notes".len() as u64);
+        // Nothing inside the unit is planned on its own
+        let inside = plan.operations.iter().filter(|op| op.source.starts_with(root.join("proj"))).count();
+        assert_eq!(inside, 1);
+
+        plan::apply(&plan).unwrap();
+        let moved = root.join(PROJECTS_CATEGORY).join("proj");
+        assert_eq!(fs::read_to_string(moved.join("a.txt")).unwrap(), "project notes");
+        assert!(moved.join(".git/HEAD").is_file());
+        assert!(!root.join("proj").exists());
+
+        undo::undo(&root, None, false).unwrap();
+        assert_eq!(fs::read_to_string(root.join("proj/a.txt")).unwrap(), "project notes");
+        assert!(root.join("proj/.git/HEAD").is_file());
+        assert!(!moved.exists());
     }
 }
@@ -0,0 +1,312 @@
+use crate::organizer::metadata::hash_path;
+use crate::organizer::renamer::PlacementMode;
+use crate::organizer::undo::UndoAction;
+use crate::organizer::utils::dir_size;
+use crate::organizer::{transfer, trash, undo, wal};
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What applying an operation does to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlanAction {
+    #[default]
+    Move,
+    /// Move over an existing (older) destination
+    Replace,
+    /// The destination already holds identical content; delete the source
+    RemoveSource,
+    /// Leave the source alone; kept in the plan so reviewers see it
+    Skip,
+}
+
+/// A single reviewed move. `size` and `modified` fingerprint the source at planning
+/// time so `apply` can tell when it changed underneath the plan.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlannedMove {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub category: String,
+    /// Matching rule or AI, as reported by the classifier
+    pub reason: String,
+    #[serde(default)]
+    pub action: PlanAction,
+    /// Move, copy or link; `action` still decides whether anything is placed at all
+    #[serde(default)]
+    pub mode: PlacementMode,
+    /// How a clash with an existing or already planned destination was resolved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collision: Option<String>,
+    /// Source of the group's primary file, for files that move as one group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<PathBuf>,
+    pub size: u64,
+    pub modified: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Plan {
+    pub root: PathBuf,
+    pub created: DateTime<Local>,
+    pub operations: Vec<PlannedMove>,
+}
+
+impl Plan {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root, created: Local::now(), operations: Vec::new() }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read plan at {:?}", path))?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        for op in &self.operations {
+            // Moves keep the historical output; other modes name themselves
+            let verb = match op.mode {
+                PlacementMode::Move => String::new(),
+                mode => format!("{} ", mode.verb()),
+            };
+            match op.action {
+                PlanAction::Move => println!("[DRY-RUN] {}{:?} -> {:?}", verb, op.source, op.destination),
+                PlanAction::Replace => println!("[DRY-RUN] {}{:?} -> {:?} (overwrite)", verb, op.source, op.destination),
+                PlanAction::RemoveSource => println!("[DRY-RUN] remove {:?} (duplicate of {:?})", op.source, op.destination),
+                PlanAction::Skip => println!("[DRY-RUN] skip {:?}", op.source),
+            }
+            println!("          {} via {}", op.category, op.reason);
+            if let Some(collision) = &op.collision {
+                println!("          collision: {}", collision);
+            }
+        }
+        println!("{} operation(s) planned", self.operations.len());
+    }
+
+    /// Checks every source is still what was planned and no destination got taken,
+    /// before anything is touched.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        // Destinations filled by earlier operations of this same plan
+        let mut produced = HashSet::new();
+        for op in self.operations.iter().filter(|op| op.action != PlanAction::Skip) {
+            match fs::metadata(&op.source) {
+                Err(_) => problems.push(format!("{:?} no longer exists", op.source)),
+                Ok(attr) => {
+                    let modified = attr.modified().ok().map(DateTime::<Local>::from);
+                    // Directory units are planned with the size of their contents
+                    let size = if attr.is_dir() { dir_size(&op.source) } else { attr.len() };
+                    if size != op.size || modified != op.modified {
+                        problems.push(format!("{:?} changed since the plan was made", op.source));
+                    }
+                }
+            }
+            match op.action {
+                PlanAction::Move if op.destination.exists() => {
+                    problems.push(format!("{:?} already exists", op.destination));
+                }
+                PlanAction::RemoveSource if !op.destination.exists() && !produced.contains(&op.destination) => {
+                    problems.push(format!("{:?} is gone, cannot drop its duplicate", op.destination));
+                }
+                _ => {}
+            }
+            if op.action != PlanAction::RemoveSource {
+                produced.insert(&op.destination);
+            }
+        }
+        if !problems.is_empty() {
+            bail!("Plan is out of date:\n  {}", problems.join("\n  "));
+        }
+        Ok(())
+    }
+}
+
+/// Executes a plan exactly as written, journaled so a failure or crash can be rolled
+/// back. The caller must hold the root lock.
+pub fn apply(plan: &Plan) -> Result<()> {
+    plan.validate()?;
+    if !trash::AVAILABLE && plan.operations.iter().any(|op| op.action == PlanAction::Replace) {
+        bail!("The plan overwrites files, which go to the trash first, and this platform has no trash support");
+    }
+    let root = plan.root.as_path();
+
+    let mut intents = wal::IntentLog::create(root)?;
+    for (seq, op) in plan.operations.iter().enumerate() {
+        intents.planned(seq, op)?;
+    }
+
+    if let Err(e) = execute(plan, &mut intents) {
+        eprintln!("Organization failed ({}); rolling back this run", e);
+        drop(intents);
+        wal::recover(root, wal::RecoverAction::Rollback)?;
+        return Err(e);
+    }
+    intents.commit()
+}
+
+fn execute(plan: &Plan, intents: &mut wal::IntentLog) -> Result<()> {
+    let root = plan.root.as_path();
+    // Appended to (not replaced) so earlier runs stay undoable
+    let mut history = undo::load_history(root)?;
+    let session_id = history.begin_session(std::env::args().collect::<Vec<_>>().join(" "));
+
+    for (seq, op) in plan.operations.iter().enumerate() {
+        match op.action {
+            PlanAction::Skip => {
+                println!("Skipped {:?}", op.source);
+                continue;
+            }
+            PlanAction::Move if op.destination.exists() => {
+                bail!("{:?} appeared while applying the plan", op.destination);
+            }
+            PlanAction::RemoveSource if hash_path(&op.source)? != hash_path(&op.destination)? => {
+                bail!("{:?} no longer matches {:?}", op.source, op.destination);
+            }
+            _ => {}
+        }
+        if let Some(parent) = op.destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        intents.started(seq, op)?;
+        // The displaced file and the file replacing it are undone together
+        let group = match op.action {
+            PlanAction::Replace => {
+                let group = op.group.clone().or_else(|| Some(op.source.clone()));
+                trash_replaced(&mut history, root, session_id, &op.destination, group.clone())?;
+                group
+            }
+            _ => op.group.clone(),
+        };
+        if op.action == PlanAction::RemoveSource {
+            fs::remove_file(&op.source)
+                .with_context(|| format!("Failed to remove duplicate {:?}", op.source))?;
+            history.record(root, session_id, UndoAction::RemovedDuplicate, op.source.clone(), op.destination.clone(), op.group.clone())?;
+            println!("Removed {:?} (identical to {:?})", op.source, op.destination);
+        } else {
+            transfer::place(op.mode, &op.source, &op.destination)?;
+            history.record(root, session_id, UndoAction::from(op.mode), op.source.clone(), op.destination.clone(), group)?;
+            match op.mode {
+                PlacementMode::Move => println!("Processed {:?} -> {:?}", op.source, op.destination),
+                mode => println!("Placed ({}) {:?} -> {:?}", mode.verb(), op.source, op.destination),
+            }
+        }
+        intents.done(seq, op)?;
+    }
+
+    Ok(())
+}
+
+/// Moves the file a `Replace` overwrites to the trash and journals it, so undo can
+/// bring it back. Nothing to do when an interrupted run already moved it.
+pub(crate) fn trash_replaced(
+    history: &mut undo::UndoHistory,
+    root: &Path,
+    session_id: u64,
+    destination: &Path,
+    group: Option<PathBuf>,
+) -> Result<()> {
+    if fs::symlink_metadata(destination).is_err() {
+        return Ok(());
+    }
+    let location = trash::trash(destination)
+        .with_context(|| format!("Cannot move {:?} out of the way to overwrite it", destination))?;
+    history.record(root, session_id, UndoAction::Trashed, destination.to_path_buf(), location, group)?;
+    println!("Moved the older {:?} to the trash", destination);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn planned(source: PathBuf, destination: PathBuf) -> PlannedMove {
+        let attr = fs::metadata(&source).unwrap();
+        PlannedMove {
+            size: if attr.is_dir() { dir_size(&source) } else { attr.len() },
+            modified: attr.modified().ok().map(DateTime::<Local>::from),
+            source,
+            destination,
+            category: "Documents".to_string(),
+            reason: "test".to_string(),
+            action: PlanAction::Move,
+            mode: PlacementMode::Move,
+            collision: None,
+            group: None,
+        }
+    }
+
+    /// A loose file and a directory unit, planned into `Documents` and `Projects`.
+    fn sample() -> (tempfile::TempDir, Plan) {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(tmp.path()).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::create_dir_all(root.join("proj/src")).unwrap();
+        fs::write(root.join("proj/src/main.rs"), "fn main() {}").unwrap();
+
+        let mut plan = Plan::new(root.clone());
+        plan.operations.push(planned(root.join("a.txt"), root.join("Documents/a.txt")));
+        plan.operations.push(planned(root.join("proj"), root.join("Projects/proj")));
+        (tmp, plan)
+    }
+
+    #[test]
+    fn saved_plan_round_trips_and_applies() {
+        let (_tmp, plan) = sample();
+        let out = tempfile::tempdir().unwrap();
+        let path = out.path().join("plan.json");
+        plan.save(&path).unwrap();
+        let loaded = Plan::load(&path).unwrap();
+
+        assert_eq!(loaded.root, plan.root);
+        assert_eq!(loaded.operations.len(), 2);
+        for (a, b) in loaded.operations.iter().zip(&plan.operations) {
+            assert_eq!((&a.source, &a.destination, a.size, a.modified), (&b.source, &b.destination, b.size, b.modified));
+            assert_eq!((a.action, a.mode), (b.action, b.mode));
+        }
+
+        apply(&loaded).unwrap();
+        let root = &plan.root;
+        assert_eq!(fs::read_to_string(root.join("Documents/a.txt")).unwrap(), "a");
+        assert!(root.join("Projects/proj/src/main.rs").is_file());
+        assert!(!root.join("a.txt").exists() && !root.join("proj").exists());
+    }
+
+    #[test]
+    fn changed_source_makes_the_plan_stale() {
+        let (_tmp, plan) = sample();
+        fs::write(plan.root.join("a.txt"), "grown since").unwrap();
+
+        let err = apply(&plan).unwrap_err().to_string();
+        assert!(err.contains("Plan is out of date"), "{}", err);
+        // Validation runs before anything moves
+        assert!(plan.root.join("proj").is_dir());
+        assert!(!plan.root.join("Projects").exists());
+    }
+
+    #[test]
+    fn changed_directory_unit_makes_the_plan_stale() {
+        let (_tmp, plan) = sample();
+        fs::write(plan.root.join("proj/src/lib.rs"), "pub fn added() {}").unwrap();
+        assert!(plan.validate().is_err());
+    }
+
+    #[test]
+    fn taken_destination_makes_the_plan_stale() {
+        let (_tmp, plan) = sample();
+        fs::create_dir(plan.root.join("Documents")).unwrap();
+        fs::write(plan.root.join("Documents/a.txt"), "someone else").unwrap();
+
+        let err = plan.validate().unwrap_err().to_string();
+        assert!(err.contains("already exists"), "{}", err);
+    }
+}
@@ -1,55 +1,350 @@
+use crate::constants::PROJECTS_CATEGORY;
 use crate::models::FileInfo;
-use std::path::PathBuf;
-use anyhow::{Result, Context};
+use crate::organizer::classifier::Classification;
+use crate::organizer::companions::{shared_stem, stem};
+use crate::organizer::metadata::hash_path;
+use crate::organizer::plan::{PlanAction, PlannedMove};
+use crate::organizer::scanner::{AtomicDir, AtomicPolicy};
+use crate::organizer::template::{Template, sanitize_file_name};
+use crate::organizer::utils::dir_size;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What to do when the destination of a move is already taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionPolicy {
+    /// Append `_1`, `_2`, ... to the stem
+    #[default]
+    Suffix,
+    /// Append the source's modification time
+    Timestamp,
+    /// Append the first characters of the source's blake3 hash
+    Hash,
+    /// Leave the source where it is
+    Skip,
+    /// Replace the existing file if it is older than the source, otherwise skip.
+    /// The replaced file goes to the trash, where undo finds it
+    OverwriteIfOlder,
+    /// Remove the source if it is byte-identical to the existing file, otherwise suffix
+    Dedupe,
+}
+
+/// How a file gets to its destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlacementMode {
+    /// Rename the file into the organized tree
+    #[default]
+    Move,
+    /// Copy it, leaving the original in place
+    Copy,
+    /// Hardlink it, so both names share the same data (same filesystem only)
+    Hardlink,
+    /// Create a symlink pointing back at the original
+    Symlink,
+}
+
+impl PlacementMode {
+    /// Whether the source stays where it is.
+    pub fn keeps_source(self) -> bool {
+        self != PlacementMode::Move
+    }
+
+    pub fn verb(self) -> &'static str {
+        match self {
+            PlacementMode::Move => "move",
+            PlacementMode::Copy => "copy",
+            PlacementMode::Hardlink => "hardlink",
+            PlacementMode::Symlink => "symlink",
+        }
+    }
+}
 
 pub struct Renamer {
-    pub dry_run: bool,
     pub base_path: PathBuf,
+    /// Global destination template, used unless the matching rule brings its own
+    pub output_pattern: Template,
+    pub collision_policy: CollisionPolicy,
+    /// Placement used unless the matching rule sets its own
+    pub mode: PlacementMode,
 }
 
 impl Renamer {
-    pub fn new(base_path: PathBuf, dry_run: bool) -> Self {
-        Self { base_path, dry_run }
-    }
-
-    pub fn get_dest_path(&self, file: &FileInfo, category: &str) -> PathBuf {
-        let mut dest = self.base_path.join(category);
-        
-        // Add date structure if available
-        if let Some(modified) = file.metadata.modified {
-            let year = modified.format("%Y").to_string();
-            let month = modified.format("%m").to_string();
-            dest = dest.join(year).join(month);
+    pub fn new(
+        base_path: PathBuf,
+        output_pattern: Template,
+        collision_policy: CollisionPolicy,
+        mode: PlacementMode,
+    ) -> Self {
+        Self { base_path, output_pattern, collision_policy, mode }
+    }
+
+    /// Works out where `file` goes without touching disk. `reserved` maps destinations
+    /// already claimed earlier in the same plan to their sources, so two sources never
+    /// share one and dry-run sees the same collisions a real run would.
+    pub fn plan_move(
+        &self,
+        file: &FileInfo,
+        classification: Classification,
+        reserved: &mut HashMap<PathBuf, PathBuf>,
+    ) -> Result<PlannedMove> {
+        let mode = classification.mode.unwrap_or(self.mode);
+        let name_template = classification.rename_to.as_deref().map(Template::parse).transpose()?;
+        let wanted = self.get_dest_path(file, &classification, name_template.as_ref(), 1)?;
+        let taken = |p: &Path| p.exists() || reserved.contains_key(p);
+
+        let (destination, action, collision) = match &name_template {
+            // The template numbers files itself, so keep counting instead of suffixing
+            Some(template) if template.uses_counter() && self.collision_policy == CollisionPolicy::Suffix => {
+                let mut counter = 1;
+                let mut destination = wanted.clone();
+                while taken(&destination) {
+                    counter += 1;
+                    destination = self.get_dest_path(file, &classification, Some(template), counter)?;
+                }
+                let collision = (counter > 1)
+                    .then(|| format!("{:?} is taken, used counter {}", wanted, counter));
+                (destination, PlanAction::Move, collision)
+            }
+            // Already organized; never compare a file against itself
+            _ if wanted == file.path => (wanted, PlanAction::Skip, Some("already in place".to_string())),
+            _ if !taken(&wanted) => (wanted, PlanAction::Move, None),
+            _ => self.resolve_collision(file, wanted, mode, reserved)?,
+        };
+        if matches!(action, PlanAction::Move | PlanAction::Replace) {
+            reserved.insert(destination.clone(), file.path.clone());
+        }
+
+        Ok(PlannedMove {
+            source: file.path.clone(),
+            destination,
+            category: classification.category,
+            reason: classification.reason,
+            action,
+            mode,
+            collision,
+            group: None,
+            size: file.metadata.size,
+            modified: file.metadata.modified,
+        })
+    }
+
+    /// Plans a companion to land next to its group's primary under the primary's new
+    /// stem: `IMG_001.xmp` follows `IMG_001.CR2 -> 2024/2024-01-01_001.cr2` as
+    /// `2024/2024-01-01_001.xmp`. Where the primary stays, so do its companions.
+    pub fn plan_companion(
+        &self,
+        file: &FileInfo,
+        primary: &PlannedMove,
+        reserved: &mut HashMap<PathBuf, PathBuf>,
+    ) -> Result<PlannedMove> {
+        let name_of = |p: &Path| p.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let (source_name, dest_name) = (name_of(&primary.source), name_of(&primary.destination));
+        // The part after the shared stem (`.CR2`) usually survives renaming; when it
+        // does not, fall back to the new name's own stem
+        let shared = shared_stem(&source_name, &file.name);
+        let suffix = &source_name[shared.len()..];
+        let new_stem = match dest_name.len().checked_sub(suffix.len()) {
+            Some(cut) if dest_name.is_char_boundary(cut) && dest_name[cut..].eq_ignore_ascii_case(suffix) => &dest_name[..cut],
+            _ => stem(&dest_name),
+        };
+        let own_suffix = file.name.get(shared.len()..).filter(|_| file.name.starts_with(shared)).unwrap_or(&file.name);
+        let wanted = primary.destination.with_file_name(format!("{}{}", new_stem, own_suffix));
+        let taken = |p: &Path| p.exists() || reserved.contains_key(p);
+
+        let (destination, action, collision) = match primary.action {
+            PlanAction::Skip => (file.path.clone(), PlanAction::Skip, Some(format!("stays with {:?}", primary.source))),
+            _ if wanted == file.path => (wanted, PlanAction::Skip, Some("already in place".to_string())),
+            _ if !taken(&wanted) => (wanted, PlanAction::Move, None),
+            _ => self.resolve_collision(file, wanted, primary.mode, reserved)?,
+        };
+        if matches!(action, PlanAction::Move | PlanAction::Replace) {
+            reserved.insert(destination.clone(), file.path.clone());
         }
 
-        dest.join(&file.name)
-    }
-
-    pub fn apply(&self, file: &FileInfo, category: &str) -> Result<PathBuf> {
-        let dest_path = self.get_dest_path(file, category);
-        let dest_dir = dest_path.parent().unwrap();
-
-        if !self.dry_run {
-            fs::create_dir_all(dest_dir)?;
-            
-            // Handle collisions
-            let mut final_path = dest_path.clone();
-            let mut count = 1;
-            while final_path.exists() {
-                let stem = dest_path.file_stem().unwrap().to_string_lossy();
-                let ext = dest_path.extension().unwrap_or_default().to_string_lossy();
-                let new_name = format!("{}_{}.{}", stem, count, ext);
-                final_path = dest_dir.join(new_name);
-                count += 1;
-            }
-
-            fs::rename(&file.path, &final_path)
-                .with_context(|| format!("Failed to move {:?} to {:?}", file.path, final_path))?;
-            
-            Ok(final_path)
-        } else {
-            Ok(dest_path)
+        Ok(PlannedMove {
+            source: file.path.clone(),
+            destination,
+            category: primary.category.clone(),
+            reason: format!("companion of {:?}", primary.source),
+            action,
+            mode: primary.mode,
+            collision,
+            group: Some(primary.source.clone()),
+            size: file.metadata.size,
+            modified: file.metadata.modified,
+        })
+    }
+
+    /// Plans an atomic directory as a single operation: left in place, or moved
+    /// whole into `Projects`. Collision policies that compare content do not apply
+    /// to directories, so any clash other than `skip` gets a counter suffix.
+    pub fn plan_directory(
+        &self,
+        dir: &AtomicDir,
+        policy: AtomicPolicy,
+        reserved: &mut HashMap<PathBuf, PathBuf>,
+    ) -> Result<PlannedMove> {
+        let attr = fs::metadata(&dir.path)?;
+        let wanted = self.base_path.join(PROJECTS_CATEGORY).join(dir.path.file_name().unwrap_or_default());
+        let taken = |p: &Path| p.exists() || reserved.contains_key(p);
+        let skip = |note: String| (dir.path.clone(), PlanAction::Skip, Some(note));
+
+        let (destination, action, collision) = match policy {
+            AtomicPolicy::Skip => skip("atomic directory, left in place".to_string()),
+            AtomicPolicy::Move if wanted == dir.path => skip("already in place".to_string()),
+            AtomicPolicy::Move if self.mode == PlacementMode::Hardlink => {
+                skip("directories cannot be hardlinked, left in place".to_string())
+            }
+            AtomicPolicy::Move if !taken(&wanted) => (wanted, PlanAction::Move, None),
+            AtomicPolicy::Move if self.collision_policy == CollisionPolicy::Skip => {
+                skip(format!("{:?} is taken, skipped", wanted))
+            }
+            AtomicPolicy::Move => {
+                let note = format!("{:?} is taken, renamed with a counter suffix", wanted);
+                (unique_path_with(&wanted, taken), PlanAction::Move, Some(note))
+            }
+        };
+        if action == PlanAction::Move {
+            reserved.insert(destination.clone(), dir.path.clone());
         }
+
+        Ok(PlannedMove {
+            source: dir.path.clone(),
+            destination,
+            category: PROJECTS_CATEGORY.to_string(),
+            reason: format!("atomic directory (contains {})", dir.marker),
+            action,
+            mode: self.mode,
+            collision,
+            group: None,
+            size: dir_size(&dir.path),
+            modified: attr.modified().ok().map(chrono::DateTime::<chrono::Local>::from),
+        })
+    }
+
+    fn resolve_collision(
+        &self,
+        file: &FileInfo,
+        wanted: PathBuf,
+        mode: PlacementMode,
+        reserved: &HashMap<PathBuf, PathBuf>,
+    ) -> Result<(PathBuf, PlanAction, Option<String>)> {
+        let taken = |p: &Path| p.exists() || reserved.contains_key(p);
+        let suffixed = |suffix: &str, how: &str| {
+            let destination = unique_path_with(&with_suffix(&wanted, suffix), taken);
+            let note = format!("{:?} is taken, {}", wanted, how);
+            (destination, PlanAction::Move, Some(note))
+        };
+
+        let resolved = match self.collision_policy {
+            CollisionPolicy::Suffix => {
+                let destination = unique_path_with(&wanted, taken);
+                let note = format!("{:?} is taken, renamed with a counter suffix", wanted);
+                (destination, PlanAction::Move, Some(note))
+            }
+            CollisionPolicy::Timestamp => {
+                let stamp = file
+                    .metadata
+                    .modified
+                    .map(|m| m.format("%Y%m%d-%H%M%S").to_string())
+                    .unwrap_or_else(|| "undated".to_string());
+                suffixed(&stamp, "renamed with a timestamp suffix")
+            }
+            CollisionPolicy::Hash => {
+                let hash = hash_path(&file.path)?;
+                suffixed(&hash[..8], "renamed with a hash suffix")
+            }
+            CollisionPolicy::Skip => {
+                (wanted.clone(), PlanAction::Skip, Some(format!("{:?} is taken, skipped", wanted)))
+            }
+            CollisionPolicy::OverwriteIfOlder => {
+                if reserved.contains_key(&wanted) {
+                    suffixed("", "claimed earlier in this run, renamed with a counter suffix")
+                } else if is_older(&wanted, file) {
+                    let note = format!("{:?} is older and will be moved to the trash", wanted);
+                    (wanted, PlanAction::Replace, Some(note))
+                } else {
+                    (wanted.clone(), PlanAction::Skip, Some(format!("{:?} is newer, skipped", wanted)))
+                }
+            }
+            CollisionPolicy::Dedupe => {
+                // Compare against whatever will be sitting there by then
+                let existing = reserved.get(&wanted).unwrap_or(&wanted);
+                let identical = hash_path(existing)? == hash_path(&file.path)?;
+                if identical && mode.keeps_source() {
+                    // Nothing to place, and the source is meant to stay
+                    (wanted.clone(), PlanAction::Skip, Some(format!("identical to {:?}, skipped", existing)))
+                } else if identical {
+                    let note = format!("identical to {:?}, source will be removed", existing);
+                    (wanted, PlanAction::RemoveSource, Some(note))
+                } else {
+                    suffixed("", "differs, renamed with a counter suffix")
+                }
+            }
+        };
+        Ok(resolved)
+    }
+
+    /// The rule's name template wins, then a name proposed in `suggested_name`
+    /// (e.g. by the AI), then the original name.
+    pub fn get_dest_path(
+        &self,
+        file: &FileInfo,
+        classification: &Classification,
+        name_template: Option<&Template>,
+        counter: u32,
+    ) -> Result<PathBuf> {
+        let category = &classification.category;
+        let dir = match &classification.output_pattern {
+            Some(pattern) => Template::parse(pattern)?.render_dir(file, category)?,
+            None => self.output_pattern.render_dir(file, category)?,
+        };
+        let name = match (name_template, &file.suggested_name) {
+            (Some(template), _) => template.render_name(file, category, counter)?,
+            (None, Some(suggested)) if !sanitize_file_name(suggested).is_empty() => sanitize_file_name(suggested),
+            _ => file.name.clone(),
+        };
+        Ok(self.base_path.join(dir).join(name))
+    }
+}
+
+/// Handle collisions by appending `_1`, `_2`, ... to the file stem.
+pub fn unique_path(dest_path: &Path) -> PathBuf {
+    unique_path_with(dest_path, |p| p.exists())
+}
+
+fn unique_path_with(dest_path: &Path, taken: impl Fn(&Path) -> bool) -> PathBuf {
+    let mut final_path = dest_path.to_path_buf();
+    let mut count = 1;
+    while taken(&final_path) {
+        final_path = with_suffix(dest_path, &count.to_string());
+        count += 1;
+    }
+    final_path
+}
+
+/// `dir/stem.ext` -> `dir/stem_suffix.ext`; files without an extension get no trailing dot.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    if suffix.is_empty() {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, suffix),
+    };
+    path.with_file_name(name)
+}
+
+fn is_older(existing: &Path, file: &FileInfo) -> bool {
+    let existing = fs::metadata(existing).and_then(|m| m.modified()).ok();
+    match (existing, file.metadata.modified) {
+        (Some(existing), Some(source)) => chrono::DateTime::<chrono::Local>::from(existing) < source,
+        _ => false,
     }
 }
@@ -0,0 +1,260 @@
+use crate::models::FileInfo;
+use crate::organizer::duplicates::KeepRules;
+use crate::organizer::utils::format_size;
+use anyhow::Result;
+use base64::Engine;
+use chrono::{DateTime, Local};
+use image::ImageFormat;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+
+/// Longest side of the thumbnails embedded in HTML reports
+const THUMBNAIL_SIZE: u32 = 160;
+
+/// How `duplicates` lists what it found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReportFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One object per set, for scripts
+    Json,
+    /// One row per file, for spreadsheets
+    Csv,
+    /// A standalone page with thumbnails of pictures, for review before `--remove`
+    Html,
+}
+
+/// One set of duplicates as reported, with the copy `--remove` would keep first.
+#[derive(Debug, Serialize)]
+pub struct DuplicateSet {
+    pub hash: String,
+    /// Size of the kept copy
+    pub size: u64,
+    /// Bytes `--remove` would free: every copy that is neither kept nor protected
+    pub wasted_bytes: u64,
+    pub keep: PathBuf,
+    pub files: Vec<ReportedFile>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: Option<DateTime<Local>>,
+    pub keep: bool,
+    pub protected: bool,
+    /// Similarity to the first file of the set, for `--similar-images` and `--similar-text`
+    pub similarity: Option<f64>,
+}
+
+/// Ranks every set with `rules` and orders the sets by reclaimable space, largest first.
+pub fn build(duplicates: &HashMap<String, Vec<FileInfo>>, rules: &KeepRules) -> Vec<DuplicateSet> {
+    let mut sets: Vec<DuplicateSet> = duplicates
+        .iter()
+        .map(|(hash, files)| {
+            let mut files = files.clone();
+            rules.rank(&mut files);
+            let files: Vec<ReportedFile> = files
+                .iter()
+                .enumerate()
+                .map(|(index, file)| ReportedFile {
+                    path: file.path.clone(),
+                    size: fs::metadata(&file.path).map(|attr| attr.len()).unwrap_or(file.metadata.size),
+                    modified: file
+                        .metadata
+                        .modified
+                        .or_else(|| fs::metadata(&file.path).and_then(|attr| attr.modified()).ok().map(DateTime::from)),
+                    keep: index == 0,
+                    protected: index > 0 && rules.is_protected(&file.path),
+                    similarity: file.metadata.extra.get("similarity").and_then(|s| s.parse().ok()),
+                })
+                .collect();
+            DuplicateSet {
+                hash: hash.clone(),
+                size: files[0].size,
+                wasted_bytes: files.iter().filter(|f| !f.keep && !f.protected).map(|f| f.size).sum(),
+                keep: files[0].path.clone(),
+                files,
+            }
+        })
+        .collect();
+    sets.sort_by(|a, b| b.wasted_bytes.cmp(&a.wasted_bytes).then_with(|| a.hash.cmp(&b.hash)));
+    sets
+}
+
+pub fn write(sets: &[DuplicateSet], format: ReportFormat, out: &mut impl Write) -> Result<()> {
+    match format {
+        ReportFormat::Text => write_text(sets, out),
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, sets)?;
+            writeln!(out)?;
+            Ok(())
+        }
+        ReportFormat::Csv => write_csv(sets, out),
+        ReportFormat::Html => write_html(sets, out),
+    }
+}
+
+fn write_text(sets: &[DuplicateSet], out: &mut impl Write) -> Result<()> {
+    for set in sets {
+        writeln!(
+            out,
+            "Hash {}: {} duplicates, {} reclaimable",
+            set.hash,
+            set.files.len(),
+            format_size(set.wasted_bytes)
+        )?;
+        for file in &set.files {
+            let mut notes = Vec::new();
+            if file.keep {
+                notes.push("keep".to_string());
+            }
+            if file.protected {
+                notes.push("protected".to_string());
+            }
+            if let Some(similarity) = file.similarity {
+                notes.push(format!("similarity {:.2}", similarity));
+            }
+            if notes.is_empty() {
+                writeln!(out, "  - {:?}", file.path)?;
+            } else {
+                writeln!(out, "  - {:?} ({})", file.path, notes.join(", "))?;
+            }
+        }
+    }
+    let total: u64 = sets.iter().map(|set| set.wasted_bytes).sum();
+    writeln!(out, "{} set(s), {} reclaimable", sets.len(), format_size(total))?;
+    Ok(())
+}
+
+fn write_csv(sets: &[DuplicateSet], out: &mut impl Write) -> Result<()> {
+    writeln!(out, "hash,wasted_bytes,path,size,modified,keep,protected,similarity")?;
+    for set in sets {
+        for file in &set.files {
+            let fields = [
+                set.hash.clone(),
+                set.wasted_bytes.to_string(),
+                file.path.display().to_string(),
+                file.size.to_string(),
+                file.modified.map(|m| m.to_rfc3339()).unwrap_or_default(),
+                file.keep.to_string(),
+                file.protected.to_string(),
+                file.similarity.map(|s| format!("{:.2}", s)).unwrap_or_default(),
+            ];
+            let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+            writeln!(out, "{}", row.join(","))?;
+        }
+    }
+    Ok(())
+}
+
+/// Quotes a field when it holds a separator, quote or line break (RFC 4180).
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn write_html(sets: &[DuplicateSet], out: &mut impl Write) -> Result<()> {
+    let pictures: Vec<&Path> = sets
+        .iter()
+        .flat_map(|set| &set.files)
+        .map(|file| file.path.as_path())
+        .filter(|path| ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled()))
+        .collect();
+    let thumbnails: HashMap<&Path, String> =
+        pictures.into_par_iter().filter_map(|path| Some((path, thumbnail(path).ok()?))).collect();
+
+    let total: u64 = sets.iter().map(|set| set.wasted_bytes).sum();
+    writeln!(out, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Duplicate report</title>")?;
+    writeln!(
+        out,
+        "<style>body{{font-family:sans-serif;margin:2em}}table{{border-collapse:collapse;margin-bottom:2em}}\
+         td,th{{border:1px solid #ccc;padding:4px 8px;text-align:left;vertical-align:middle}}\
+         tr.keep{{background:#e6f4e6}}tr.protected{{background:#eef}}img{{display:block}}</style>\n</head>\n<body>"
+    )?;
+    writeln!(
+        out,
+        "<h1>Duplicate report</h1>\n<p>{} set(s), {} reclaimable. Kept copies are green, protected copies blue.</p>",
+        sets.len(),
+        format_size(total)
+    )?;
+    for set in sets {
+        writeln!(
+            out,
+            "<h2>{}</h2>\n<p>{} file(s), {} reclaimable</p>",
+            escape(&set.hash),
+            set.files.len(),
+            format_size(set.wasted_bytes)
+        )?;
+        writeln!(out, "<table>\n<tr><th></th><th>Path</th><th>Size</th><th>Modified</th><th>Action</th></tr>")?;
+        for file in &set.files {
+            let (class, action) = match (file.keep, file.protected) {
+                (true, _) => ("keep", "keep"),
+                (_, true) => ("protected", "protected"),
+                _ => ("", "remove"),
+            };
+            let action = match file.similarity {
+                Some(similarity) => format!("{} (similarity {:.2})", action, similarity),
+                None => action.to_string(),
+            };
+            let preview = match thumbnails.get(file.path.as_path()) {
+                Some(data) => format!("<img src=\"data:image/jpeg;base64,{}\" alt=\"\">", data),
+                None => String::new(),
+            };
+            writeln!(
+                out,
+                "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                class,
+                preview,
+                escape(&file.path.display().to_string()),
+                format_size(file.size),
+                file.modified.map(|m| m.format("%Y-%m-%d %H:%M").to_string()).unwrap_or_default(),
+                action
+            )?;
+        }
+        writeln!(out, "</table>")?;
+    }
+    writeln!(out, "</body>\n</html>")?;
+    Ok(())
+}
+
+/// A small JPEG of the picture, base64-encoded for a data URI.
+fn thumbnail(path: &Path) -> Result<String> {
+    let picture = image::open(path)?.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+    let mut jpeg = Cursor::new(Vec::new());
+    picture.write_to(&mut jpeg, ImageFormat::Jpeg)?;
+    Ok(base64::engine::general_purpose::STANDARD.encode(jpeg.into_inner()))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_csv_fields_only_when_needed() {
+        assert_eq!(csv_field("/photos/a.jpg"), "/photos/a.jpg");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("/photos/a,b.jpg"), "\"/photos/a,b.jpg\"");
+        assert_eq!(csv_field("say \"cheese\".jpg"), "\"say \"\"cheese\"\".jpg\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("carriage\rreturn"), "\"carriage\rreturn\"");
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(escape("<a href=\"x\">&</a>"), "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;");
+    }
+}
@@ -1,4 +1,5 @@
 use crate::models::FileInfo;
+use crate::organizer::renamer::PlacementMode;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 use anyhow::{Result, Context};
@@ -9,7 +10,19 @@ pub struct Rule {
     pub match_extension: Option<Vec<String>>,
     pub match_mime: Option<String>,
     pub match_name_regex: Option<String>,
+    /// Regex over extracted document text, e.g. the start of a PDF
+    #[serde(default)]
+    pub match_text_regex: Option<String>,
     pub move_to: String,
+    /// Destination template overriding the global `default_output_pattern`
+    #[serde(default)]
+    pub output_pattern: Option<String>,
+    /// File name template, e.g. `{date_original:%Y-%m-%d}_{camera_model}_{counter}.{ext}`
+    #[serde(default)]
+    pub rename_to: Option<String>,
+    /// `move`, `copy`, `hardlink` or `symlink`; overrides `--mode` for matching files
+    #[serde(default)]
+    pub mode: Option<PlacementMode>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -48,14 +61,42 @@ pub fn match_rule(file: &FileInfo, rule: &Rule) -> bool {
         }
     }
 
+    // Match extracted text; files without any never match
+    if let Some(regex_str) = &rule.match_text_regex {
+        if let Ok(re) = Regex::new(regex_str) {
+            if !file.metadata.extra.get("text").is_some_and(|text| re.is_match(text)) {
+                return false;
+            }
+        }
+    }
+
     true
 }
 
-pub fn classify_by_rules(file: &FileInfo, config: &RulesConfig) -> Option<String> {
-    for rule in &config.rules {
-        if match_rule(file, rule) {
-            return Some(rule.move_to.clone());
+impl Rule {
+    /// Short human readable summary of what the rule matches on
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(extensions) = &self.match_extension {
+            parts.push(format!("extension in [{}]", extensions.join(", ")));
+        }
+        if let Some(mime) = &self.match_mime {
+            parts.push(format!("mime contains {:?}", mime));
+        }
+        if let Some(regex_str) = &self.match_name_regex {
+            parts.push(format!("name matches /{}/", regex_str));
+        }
+        if let Some(regex_str) = &self.match_text_regex {
+            parts.push(format!("text matches /{}/", regex_str));
+        }
+        if parts.is_empty() {
+            "matches everything".to_string()
+        } else {
+            parts.join(", ")
         }
     }
-    None
+}
+
+pub fn find_matching_rule<'a>(file: &FileInfo, config: &'a RulesConfig) -> Option<(usize, &'a Rule)> {
+    config.rules.iter().enumerate().find(|(_, rule)| match_rule(file, rule))
 }
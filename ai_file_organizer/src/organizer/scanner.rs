@@ -1,36 +1,300 @@
+use crate::constants::{IGNORE_FILE, INTERNAL_FILES};
 use crate::models::FileInfo;
-use std::path::Path;
-use walkdir::WalkDir;
+use ignore::gitignore::Gitignore;
+use ignore::overrides::{Override, OverrideBuilder};
+use ignore::WalkBuilder;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use rayon::prelude::*;
 use anyhow::Result;
 
-pub fn scan(path: &Path) -> Result<Vec<FileInfo>> {
-    let entries: Vec<_> = WalkDir::new(path)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-        .collect();
+/// How a walk treats symbolic links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SymlinkPolicy {
+    /// Leave links out
+    #[default]
+    Skip,
+    /// Follow links into the files and folders they point at
+    Follow,
+    /// Treat each link as a file of its own; organizing it moves the link
+    AsFile,
+}
 
-    let files: Vec<FileInfo> = entries
-        .into_par_iter()
-        .map(|entry| {
-            FileInfo::new(entry.path().to_path_buf())
+/// Which timestamp `--older-than`/`--newer-than` look at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum AgeBasis {
+    /// Last content modification
+    #[default]
+    Mtime,
+    /// Last status change (creation time on Windows)
+    Ctime,
+}
+
+/// What `organize` does with an atomic directory (a project checkout, a photo or
+/// Calibre library, a bundle).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AtomicPolicy {
+    /// Leave it where it is
+    #[default]
+    Skip,
+    /// Move the whole directory into the `Projects` category
+    Move,
+}
+
+/// A directory the walk treated as one unit instead of descending into it.
+#[derive(Debug, Clone)]
+pub struct AtomicDir {
+    pub path: PathBuf,
+    /// The marker that identified it, e.g. `Cargo.toml`
+    pub marker: String,
+}
+
+/// What a walk skips, shared by every command that scans a folder. On top of these,
+/// `.organizerignore` files (gitignore syntax) apply to their folder and below.
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Names or gitignore patterns from the config's `ignore_list`
+    pub ignore_list: Vec<String>,
+    /// Keep only files matching one of these globs; everything when empty
+    pub include: Vec<String>,
+    /// Skip files and folders matching any of these globs
+    pub exclude: Vec<String>,
+    /// Depth 1 is the files directly inside the scanned folder
+    pub min_depth: Option<usize>,
+    pub max_depth: Option<usize>,
+    /// Size bounds in bytes, both exclusive
+    pub larger_than: Option<u64>,
+    pub smaller_than: Option<u64>,
+    pub older_than: Option<Duration>,
+    pub newer_than: Option<Duration>,
+    pub age_by: AgeBasis,
+    pub symlinks: SymlinkPolicy,
+    /// Never descend into a folder on another filesystem (e.g. a mounted share)
+    pub one_file_system: bool,
+    /// Entries (`.git`, `Cargo.toml`) whose presence makes a directory atomic;
+    /// `*.ext` matches the directory's own name instead (`*.photoslibrary`)
+    pub atomic_markers: Vec<String>,
+    /// Treat marked directories as units that are never descended into. Only
+    /// `organize` moves whole directories; other commands look inside them.
+    pub atomic_units: bool,
+}
+
+impl ScanOptions {
+    /// `include` whitelists, `exclude` and `ignore_list` blacklist; overrides beat
+    /// `.organizerignore` files.
+    fn overrides(&self, root: &Path) -> Result<Override> {
+        let mut builder = OverrideBuilder::new(root);
+        for glob in &self.include {
+            builder.add(glob)?;
+        }
+        for glob in self.exclude.iter().chain(&self.ignore_list) {
+            builder.add(&format!("!{}", glob))?;
+        }
+        Ok(builder.build()?)
+    }
+
+    /// A matcher for single paths under `root`, for callers that do not walk
+    /// (e.g. the watcher). Every `.organizerignore` below `root` is parsed here, so
+    /// build a new filter when one of them changes.
+    pub fn path_filter(&self, root: &Path) -> Result<PathFilter> {
+        let mut ignore_files = HashMap::new();
+        let files = walkdir::WalkDir::new(root)
+            .follow_links(self.symlinks == SymlinkPolicy::Follow)
+            .same_file_system(self.one_file_system)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file() && e.file_name() == IGNORE_FILE);
+        for file in files {
+            let (matcher, err) = Gitignore::new(file.path());
+            if let Some(err) = err {
+                log::warn!("Problem in {:?}: {}", file.path(), err);
+            }
+            if let Some(dir) = file.path().parent() {
+                ignore_files.insert(dir.to_path_buf(), matcher);
+            }
+        }
+        Ok(PathFilter {
+            root: root.to_path_buf(),
+            root_device: device(&fs::metadata(root)?),
+            overrides: self.overrides(root)?,
+            ignore_files,
+            options: self.clone(),
         })
-        .collect();
+    }
+
+    /// The marker that makes `dir` atomic, if any. Always None unless `atomic_units`.
+    pub fn atomic_marker(&self, dir: &Path) -> Option<&str> {
+        if !self.atomic_units {
+            return None;
+        }
+        let name = dir.file_name()?.to_string_lossy();
+        self.atomic_markers
+            .iter()
+            .find(|marker| match marker.strip_prefix('*') {
+                Some(suffix) => name.ends_with(suffix),
+                None => fs::symlink_metadata(dir.join(marker.as_str())).is_ok(),
+            })
+            .map(String::as_str)
+    }
+
+    /// The depth, size and age checks for a file `depth` levels below the root.
+    fn keeps(&self, depth: usize, attr: &Metadata) -> bool {
+        if self.min_depth.is_some_and(|min| depth < min) || self.max_depth.is_some_and(|max| depth > max) {
+            return false;
+        }
+        let size = attr.len();
+        if self.larger_than.is_some_and(|min| size <= min) || self.smaller_than.is_some_and(|max| size >= max) {
+            return false;
+        }
+        if self.older_than.is_none() && self.newer_than.is_none() {
+            return true;
+        }
+        let Some(age) = self.age(attr) else {
+            return false;
+        };
+        !(self.older_than.is_some_and(|min| age <= min) || self.newer_than.is_some_and(|max| age >= max))
+    }
+
+    fn age(&self, attr: &Metadata) -> Option<Duration> {
+        let time = match self.age_by {
+            AgeBasis::Mtime => attr.modified().ok()?,
+            AgeBasis::Ctime => changed(attr)?,
+        };
+        // Timestamps in the future count as brand new
+        Some(SystemTime::now().duration_since(time).unwrap_or_default())
+    }
+}
+
+#[cfg(unix)]
+fn changed(attr: &Metadata) -> Option<SystemTime> {
+    use std::os::unix::fs::MetadataExt;
+    let seconds = u64::try_from(attr.ctime()).ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::new(seconds, attr.ctime_nsec() as u32))
+}
+
+#[cfg(windows)]
+fn changed(attr: &Metadata) -> Option<SystemTime> {
+    attr.created().ok()
+}
 
+#[cfg(unix)]
+fn device(attr: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(attr.dev())
+}
+
+#[cfg(windows)]
+fn device(_attr: &Metadata) -> Option<u64> {
+    None
+}
+
+/// Answers "would a scan of `root` skip this path?" without walking.
+pub struct PathFilter {
+    root: PathBuf,
+    root_device: Option<u64>,
+    overrides: Override,
+    /// Parsed `.organizerignore` files by the folder they sit in
+    ignore_files: HashMap<PathBuf, Gitignore>,
+    options: ScanOptions,
+}
+
+impl PathFilter {
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return true;
+        };
+        if let Ok(link) = fs::symlink_metadata(path) {
+            let attr = match self.options.symlinks {
+                SymlinkPolicy::Skip if link.file_type().is_symlink() => return true,
+                SymlinkPolicy::Follow => fs::metadata(path).unwrap_or(link),
+                _ => link,
+            };
+            if !self.options.keeps(relative.components().count(), &attr) {
+                return true;
+            }
+            if self.options.one_file_system && device(&attr) != self.root_device {
+                return true;
+            }
+        }
+        if self.overrides.matched(path, path.is_dir()).is_ignore() {
+            return true;
+        }
+        // A skipped folder hides everything below it, and atomic folders are never split
+        let folders: Vec<&Path> = relative.ancestors().skip(1).filter(|p| !p.as_os_str().is_empty()).collect();
+        if folders.iter().any(|dir| {
+            let dir = self.root.join(dir);
+            self.overrides.matched(&dir, true).is_ignore() || self.options.atomic_marker(&dir).is_some()
+        }) {
+            return true;
+        }
+
+        // The deepest `.organizerignore` with an opinion wins, so nested files can re-include
+        let mut dirs: Vec<PathBuf> = folders.iter().map(|dir| self.root.join(dir)).collect();
+        dirs.push(self.root.clone());
+        for matcher in dirs.iter().filter_map(|dir| self.ignore_files.get(dir)) {
+            let matched = matcher.matched_path_or_any_parents(path, path.is_dir());
+            if !matched.is_none() {
+                return matched.is_ignore();
+            }
+        }
+        false
+    }
+}
+
+pub fn scan(path: &Path, options: &ScanOptions) -> Result<Vec<FileInfo>> {
+    let (files, atomic) = scan_units(path, options)?;
+    for dir in atomic {
+        log::info!("Not descending into {:?} (contains {})", dir.path, dir.marker);
+    }
     Ok(files)
 }
 
-#[allow(dead_code)]
-pub fn scan_with_ignore(path: &Path, ignore_list: &[String]) -> Result<Vec<FileInfo>> {
-    let entries: Vec<_> = WalkDir::new(path)
-        .into_iter()
-        .filter_entry(|e| {
-            let name = e.file_name().to_string_lossy().to_string();
-            !ignore_list.contains(&name) && !name.starts_with('.')
+/// Like `scan`, but also returns the atomic directories it stopped at. Their
+/// contents are never listed, so nothing inside them gets organized on its own.
+pub fn scan_units(path: &Path, options: &ScanOptions) -> Result<(Vec<FileInfo>, Vec<AtomicDir>)> {
+    let atomic = Arc::new(Mutex::new(Vec::new()));
+    let found = Arc::clone(&atomic);
+    let markers = options.clone();
+
+    let walker = WalkBuilder::new(path)
+        // No hidden-file, .gitignore or parent-folder rules; only ours
+        .standard_filters(false)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        .overrides(options.overrides(path)?)
+        .max_depth(options.max_depth)
+        .follow_links(options.symlinks == SymlinkPolicy::Follow)
+        .same_file_system(options.one_file_system)
+        .filter_entry(move |entry| {
+            // The root itself is what the user asked for, even if it is a project
+            if entry.depth() == 0 || !entry.file_type().is_some_and(|t| t.is_dir()) {
+                return true;
+            }
+            match markers.atomic_marker(entry.path()) {
+                Some(marker) => {
+                    let dir = AtomicDir { path: entry.path().to_path_buf(), marker: marker.to_string() };
+                    found.lock().expect("scan lock poisoned").push(dir);
+                    false
+                }
+                None => true,
+            }
         })
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
+        .build();
+
+    let entries: Vec<_> = walker
+        .filter_map(|e| e.map_err(|err| log::warn!("Skipping during scan: {}", err)).ok())
+        .filter(|e| {
+            e.file_type().is_some_and(|t| {
+                t.is_file() || (t.is_symlink() && options.symlinks == SymlinkPolicy::AsFile)
+            })
+        })
+        .filter(|e| e.metadata().is_ok_and(|attr| options.keeps(e.depth(), &attr)))
+        // The organizer's own bookkeeping is never a candidate
+        .filter(|e| !INTERNAL_FILES.iter().any(|name| e.file_name() == *name))
         .collect();
 
     let files: Vec<FileInfo> = entries
@@ -40,5 +304,32 @@ pub fn scan_with_ignore(path: &Path, ignore_list: &[String]) -> Result<Vec<FileI
         })
         .collect();
 
-    Ok(files)
+    let atomic = std::mem::take(&mut *atomic.lock().expect("scan lock poisoned"));
+    Ok((files, atomic))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_filter_reads_ignore_files_once() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(tmp.path()).unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join(IGNORE_FILE), "*.log\n").unwrap();
+        fs::write(root.join("sub").join(IGNORE_FILE), "!keep.log\n").unwrap();
+
+        let filter = ScanOptions::default().path_filter(&root).unwrap();
+        assert!(filter.is_ignored(&root.join("a.log")));
+        assert!(filter.is_ignored(&root.join("sub/b.log")));
+        assert!(!filter.is_ignored(&root.join("sub/keep.log")));
+        assert!(!filter.is_ignored(&root.join("a.txt")));
+
+        // Edits only show up in a filter built afterwards
+        fs::write(root.join(IGNORE_FILE), "*.txt\n").unwrap();
+        assert!(!filter.is_ignored(&root.join("a.txt")));
+        let filter = ScanOptions::default().path_filter(&root).unwrap();
+        assert!(filter.is_ignored(&root.join("a.txt")));
+    }
 }
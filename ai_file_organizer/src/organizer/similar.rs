@@ -0,0 +1,471 @@
+use crate::models::FileInfo;
+use crate::organizer::cache::MetadataCache;
+use crate::organizer::duplicates::{distinct_files, report};
+use crate::organizer::metadata;
+use crate::organizer::utils::Progress;
+use anyhow::Result;
+use image::imageops::FilterType;
+use image::ImageFormat;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Instant;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Similarity `--threshold` defaults to for each mode
+pub const DEFAULT_IMAGE_THRESHOLD: f64 = 0.85;
+pub const DEFAULT_TEXT_THRESHOLD: f64 = 0.9;
+
+/// MinHash values per document; estimates are within about 0.03 of the true
+/// Jaccard similarity
+const SIGNATURE_LEN: usize = 128;
+/// Words per shingle, so reordered paragraphs still share most shingles
+const SHINGLE_WORDS: usize = 3;
+/// Signature rows per locality-sensitive hashing band; documents sharing a band
+/// are compared. 4 of 128 finds pairs above 0.7 almost surely
+const BAND_ROWS: usize = 4;
+
+/// How a picture is reduced to 64 bits. Both survive resizing and recompression;
+/// close pictures differ in few bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageHash {
+    /// Brightness gradients between neighbouring pixels; fast
+    Dhash,
+    /// Low frequencies of a discrete cosine transform; also copes with small edits
+    #[default]
+    Phash,
+}
+
+impl ImageHash {
+    fn key(self) -> &'static str {
+        match self {
+            ImageHash::Dhash => "dhash",
+            ImageHash::Phash => "phash",
+        }
+    }
+}
+
+/// Groups pictures whose perceptual hashes agree in at least `threshold` of
+/// their 64 bits. Hashes and dimensions are kept in the metadata cache (`extra`),
+/// so unchanged pictures are decoded once. Each member's `similarity` to the
+/// first picture of its set is recorded in `extra`.
+pub fn find_similar_images(
+    files: Vec<FileInfo>,
+    cache: &mut MetadataCache,
+    algorithm: ImageHash,
+    threshold: f64,
+) -> Result<HashMap<String, Vec<FileInfo>>> {
+    let max_bits = ((1.0 - threshold) * 64.0).round() as u32;
+    let started = Instant::now();
+    let mut images: Vec<(FileInfo, bool)> = distinct_files(files)
+        .into_iter()
+        .filter(|file| ImageFormat::from_path(&file.path).is_ok_and(|format| format.reading_enabled()))
+        .map(|mut file| {
+            let cached = cache.lookup(&mut file).unwrap_or(false);
+            (file, cached)
+        })
+        .collect();
+    let input = images.len();
+
+    let stage = format!("[1/2] {}", algorithm.key());
+    let progress = Progress::new(&stage, images.len());
+    images.par_iter_mut().for_each(|(file, cached)| {
+        if !*cached {
+            let _ = metadata::extract_metadata(file);
+        }
+        if !file.metadata.extra.contains_key(algorithm.key()) {
+            match perceptual_hash(file, algorithm) {
+                Ok(()) => *cached = false,
+                Err(e) => log::warn!("Skipping {:?}: {}", file.path, e),
+            }
+        }
+        progress.tick();
+    });
+    progress.finish();
+    for (file, cached) in &images {
+        if !cached {
+            let _ = cache.insert(file);
+        }
+    }
+
+    let mut images: Vec<(u64, FileInfo)> = images
+        .into_iter()
+        .filter_map(|(file, _)| {
+            let hash = u64::from_str_radix(file.metadata.extra.get(algorithm.key())?, 16).ok()?;
+            Some((hash, file))
+        })
+        .collect();
+    eprintln!(
+        "  {} picture(s) in, {} hashed ({:.2}s)",
+        input,
+        images.len(),
+        started.elapsed().as_secs_f64()
+    );
+
+    let started = Instant::now();
+    eprintln!("[2/2] clustering within {} bit(s)", max_bits);
+    images.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+    let mut tree = BkTree::default();
+    for (index, (hash, _)) in images.iter().enumerate() {
+        tree.insert(*hash, index);
+    }
+
+    // Each cluster is anchored at its first picture, so a chain of small
+    // differences cannot pull two very different pictures together
+    let mut taken = vec![false; images.len()];
+    let mut clusters = Vec::new();
+    for anchor in 0..images.len() {
+        if taken[anchor] {
+            continue;
+        }
+        let mut members: Vec<usize> = tree.within(images[anchor].0, max_bits).into_iter().filter(|&i| !taken[i]).collect();
+        if members.len() < 2 {
+            continue;
+        }
+        members.sort_unstable();
+        for &i in &members {
+            taken[i] = true;
+        }
+        clusters.push((images[anchor].0, members));
+    }
+
+    let sets: Vec<(u64, Vec<FileInfo>)> = clusters
+        .into_iter()
+        .map(|(hash, members)| {
+            let set = members
+                .into_iter()
+                .map(|i| {
+                    let differing = (images[i].0 ^ hash).count_ones();
+                    with_similarity(images[i].1.clone(), 1.0 - f64::from(differing) / 64.0)
+                })
+                .collect();
+            (hash, set)
+        })
+        .collect();
+    let groups: Vec<Vec<FileInfo>> = sets.iter().map(|(_, set)| set.clone()).collect();
+    report(images.len(), &groups, started);
+
+    // Anchors can share a hash, so the first member's path keeps keys apart
+    Ok(sets
+        .into_iter()
+        .map(|(hash, set)| (format!("{}:{:016x}:{}", algorithm.key(), hash, set[0].path.display()), set))
+        .collect())
+}
+
+/// Groups plain text, PDF and office documents whose word shingles overlap by
+/// at least `threshold` (Jaccard similarity, estimated by MinHash), so the same
+/// report saved as `.docx`, `.pdf` and `.txt` ends up in one set. Signatures are
+/// kept in the metadata cache; each member's `similarity` to the first document
+/// of its set is recorded in `extra`.
+pub fn find_similar_text(
+    files: Vec<FileInfo>,
+    cache: &mut MetadataCache,
+    threshold: f64,
+) -> Result<HashMap<String, Vec<FileInfo>>> {
+    let started = Instant::now();
+    let mut documents: Vec<(FileInfo, bool)> = distinct_files(files)
+        .into_iter()
+        .filter(|file| {
+            let extension = file.path.extension().map(|e| e.to_string_lossy().to_lowercase());
+            extension.is_some_and(|e| metadata::has_text(&e))
+        })
+        .map(|mut file| {
+            let cached = cache.lookup(&mut file).unwrap_or(false);
+            (file, cached)
+        })
+        .collect();
+    let input = documents.len();
+
+    let progress = Progress::new("[1/2] minhash", documents.len());
+    documents.par_iter_mut().for_each(|(file, cached)| {
+        if !*cached {
+            let _ = metadata::extract_metadata(file);
+        }
+        if !file.metadata.extra.contains_key("minhash") {
+            match metadata::extract_text(file) {
+                Ok(text) => {
+                    // Remember text-less documents too, so they are not reopened
+                    let signature = text.as_deref().map(minhash).unwrap_or_default();
+                    file.metadata.extra.insert("minhash".to_string(), signature);
+                    *cached = false;
+                }
+                Err(e) => log::warn!("Skipping {:?}: {}", file.path, e),
+            }
+        }
+        progress.tick();
+    });
+    progress.finish();
+    for (file, cached) in &documents {
+        if !cached {
+            let _ = cache.insert(file);
+        }
+    }
+
+    let mut documents: Vec<(Vec<u32>, FileInfo)> = documents
+        .into_iter()
+        .filter_map(|(file, _)| Some((parse_signature(file.metadata.extra.get("minhash")?)?, file)))
+        .collect();
+    eprintln!(
+        "  {} document(s) in, {} with text ({:.2}s)",
+        input,
+        documents.len(),
+        started.elapsed().as_secs_f64()
+    );
+
+    let started = Instant::now();
+    eprintln!("[2/2] clustering at {:.0}% similarity", threshold * 100.0);
+    documents.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+    let mut buckets: HashMap<(usize, u64), Vec<usize>> = HashMap::new();
+    for (index, (signature, _)) in documents.iter().enumerate() {
+        for (band, rows) in signature.chunks(BAND_ROWS).enumerate() {
+            buckets.entry((band, band_key(rows))).or_default().push(index);
+        }
+    }
+
+    // Anchored at the first document like picture clusters, so drafts drifting
+    // apart one edit at a time do not merge
+    let mut taken = vec![false; documents.len()];
+    let mut sets = Vec::new();
+    for anchor in 0..documents.len() {
+        if taken[anchor] {
+            continue;
+        }
+        let signature = &documents[anchor].0;
+        let mut members: Vec<usize> = signature
+            .chunks(BAND_ROWS)
+            .enumerate()
+            .flat_map(|(band, rows)| buckets[&(band, band_key(rows))].iter().copied())
+            .filter(|&i| !taken[i] && estimate(signature, &documents[i].0) >= threshold)
+            .collect();
+        members.sort_unstable();
+        members.dedup();
+        if members.len() < 2 {
+            continue;
+        }
+        for &i in &members {
+            taken[i] = true;
+        }
+        let set: Vec<FileInfo> = members
+            .into_iter()
+            .map(|i| with_similarity(documents[i].1.clone(), estimate(signature, &documents[i].0)))
+            .collect();
+        sets.push(set);
+    }
+    report(documents.len(), &sets, started);
+
+    Ok(sets.into_iter().map(|set| (format!("text:{}", set[0].path.display()), set)).collect())
+}
+
+fn with_similarity(mut file: FileInfo, similarity: f64) -> FileInfo {
+    file.metadata.extra.insert("similarity".to_string(), format!("{:.2}", similarity));
+    file
+}
+
+/// MinHash signature of the text's word shingles, as hex. Empty when the text
+/// has no words.
+fn minhash(text: &str) -> String {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if words.is_empty() {
+        return String::new();
+    }
+    let mut shingles: Vec<u64> = words
+        .windows(SHINGLE_WORDS.min(words.len()))
+        .map(|window| xxh3_64(window.join(" ").as_bytes()))
+        .collect();
+    shingles.sort_unstable();
+    shingles.dedup();
+
+    // One cheap permutation per slot: remix the shingle hash with a fixed seed
+    (0..SIGNATURE_LEN as u64)
+        .map(|slot| {
+            let seed = mix(slot.wrapping_add(1));
+            let min = shingles.iter().map(|&h| mix(h ^ seed)).min().unwrap_or(u64::MAX);
+            format!("{:08x}", min as u32)
+        })
+        .collect()
+}
+
+fn parse_signature(hex: &str) -> Option<Vec<u32>> {
+    if hex.len() != SIGNATURE_LEN * 8 {
+        return None;
+    }
+    (0..SIGNATURE_LEN).map(|i| u32::from_str_radix(hex.get(i * 8..i * 8 + 8)?, 16).ok()).collect()
+}
+
+/// Share of signature slots two documents agree in.
+fn estimate(a: &[u32], b: &[u32]) -> f64 {
+    a.iter().zip(b).filter(|(x, y)| x == y).count() as f64 / SIGNATURE_LEN as f64
+}
+
+fn band_key(rows: &[u32]) -> u64 {
+    rows.iter().fold(0u64, |key, &row| mix(key ^ u64::from(row)))
+}
+
+/// splitmix64 finalizer
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Decodes the picture and stores its hash and dimensions in `extra`.
+fn perceptual_hash(file: &mut FileInfo, algorithm: ImageHash) -> Result<()> {
+    let picture = image::open(&file.path)?;
+    let hash = match algorithm {
+        ImageHash::Dhash => dhash(&picture),
+        ImageHash::Phash => phash(&picture),
+    };
+    let extra = &mut file.metadata.extra;
+    extra.insert(algorithm.key().to_string(), format!("{:016x}", hash));
+    extra.insert("width".to_string(), picture.width().to_string());
+    extra.insert("height".to_string(), picture.height().to_string());
+    Ok(())
+}
+
+/// One bit per pixel of a 9x8 thumbnail: is it brighter than its right neighbour?
+fn dhash(picture: &image::DynamicImage) -> u64 {
+    let small = picture.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let bit = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | u64::from(bit);
+        }
+    }
+    hash
+}
+
+/// One bit per coefficient of the 8x8 lowest frequencies of a 32x32 DCT: is it
+/// above their median? The constant term is left out of the median.
+fn phash(picture: &image::DynamicImage) -> u64 {
+    const N: usize = 32;
+    let small = picture.resize_exact(N as u32, N as u32, FilterType::Triangle).to_luma8();
+    let pixels: Vec<f64> = small.pixels().map(|p| f64::from(p[0])).collect();
+
+    let cosines: Vec<f64> = (0..8 * N)
+        .map(|i| {
+            let (u, x) = (i / N, i % N);
+            ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / (2 * N) as f64).cos()
+        })
+        .collect();
+    let mut low = [0f64; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            let mut sum = 0.0;
+            for y in 0..N {
+                for x in 0..N {
+                    sum += pixels[y * N + x] * cosines[u * N + x] * cosines[v * N + y];
+                }
+            }
+            low[v * 8 + u] = sum;
+        }
+    }
+
+    let mut sorted: Vec<f64> = low[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    low.iter().fold(0u64, |hash, &c| (hash << 1) | u64::from(c > median))
+}
+
+/// Metric tree over Hamming distance, so finding everything within a threshold
+/// does not compare against every picture.
+#[derive(Default)]
+struct BkTree {
+    nodes: Vec<BkNode>,
+}
+
+struct BkNode {
+    hash: u64,
+    item: usize,
+    /// Subtrees keyed by their distance to this node
+    children: Vec<(u32, usize)>,
+}
+
+impl BkTree {
+    fn insert(&mut self, hash: u64, item: usize) {
+        let new = self.nodes.len();
+        self.nodes.push(BkNode { hash, item, children: Vec::new() });
+        if new == 0 {
+            return;
+        }
+        let mut current = 0;
+        loop {
+            let distance = (self.nodes[current].hash ^ hash).count_ones();
+            match self.nodes[current].children.iter().find(|(d, _)| *d == distance) {
+                Some(&(_, child)) => current = child,
+                None => {
+                    self.nodes[current].children.push((distance, new));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn within(&self, hash: u64, threshold: u32) -> Vec<usize> {
+        let mut found = Vec::new();
+        let mut pending = if self.nodes.is_empty() { vec![] } else { vec![0] };
+        while let Some(index) = pending.pop() {
+            let node = &self.nodes[index];
+            let distance = (node.hash ^ hash).count_ones();
+            if distance <= threshold {
+                found.push(node.item);
+            }
+            // Triangle inequality: only these subtrees can hold matches
+            pending.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| d.abs_diff(distance) <= threshold)
+                    .map(|&(_, child)| child),
+            );
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(text: &str) -> Vec<u32> {
+        parse_signature(&minhash(text)).expect("text has words")
+    }
+
+    fn words(count: usize, offset: usize) -> String {
+        (offset..offset + count).map(|i| format!("word{}", i)).collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn identical_words_match_regardless_of_case_and_punctuation() {
+        let a = signature("The quick brown fox jumps over the lazy dog.");
+        let b = signature("the QUICK brown fox -- jumps over the lazy dog");
+        assert_eq!(estimate(&a, &b), 1.0);
+    }
+
+    #[test]
+    fn one_changed_word_stays_similar() {
+        let original = words(200, 0);
+        let edited = original.replacen("word100", "changed", 1);
+        let similarity = estimate(&signature(&original), &signature(&edited));
+        assert!(similarity > 0.85 && similarity < 1.0, "similarity {}", similarity);
+    }
+
+    #[test]
+    fn unrelated_documents_do_not_match() {
+        let similarity = estimate(&signature(&words(200, 0)), &signature(&words(200, 1000)));
+        assert!(similarity < 0.1, "similarity {}", similarity);
+    }
+
+    #[test]
+    fn short_and_empty_texts() {
+        assert_eq!(signature("hello").len(), SIGNATURE_LEN);
+        assert_eq!(minhash(" .,;- "), "");
+        assert!(parse_signature("").is_none());
+        assert!(parse_signature(&"zz".repeat(SIGNATURE_LEN * 4)).is_none());
+    }
+}
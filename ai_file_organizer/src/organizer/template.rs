@@ -0,0 +1,332 @@
+use crate::models::FileInfo;
+use anyhow::{Result, anyhow, bail};
+use crate::constants::MAX_FILE_NAME_BYTES;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime};
+use std::path::PathBuf;
+
+/// Widest zero padding `{counter:N}` accepts
+const MAX_COUNTER_WIDTH: usize = 12;
+
+/// A parsed path template such as `{category}/{year}/{exif.camera_model|slug|default(unknown)}`
+/// or, for file names, `{date_original:%Y-%m-%d}_{counter:03}.{ext}`.
+#[derive(Debug, Clone)]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Placeholder { key: String, spec: Option<String>, filters: Vec<Filter> },
+}
+
+#[derive(Debug, Clone)]
+enum Filter {
+    Lower,
+    Upper,
+    Slug,
+    Default(String),
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut rest = source;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                segments.push(Segment::Literal(rest[..open].to_string()));
+            }
+            let close = rest[open..]
+                .find('}')
+                .map(|i| open + i)
+                .ok_or_else(|| anyhow!("Unclosed '{{' in template {:?}", source))?;
+            segments.push(parse_placeholder(&rest[open + 1..close], source)?);
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        Ok(Self { source: source.to_string(), segments })
+    }
+
+    /// Renders the template for `file` as a relative directory path. Placeholders
+    /// without a value render empty and empty path components are dropped, so a
+    /// missing date simply removes the date folders.
+    pub fn render_dir(&self, file: &FileInfo, category: &str) -> Result<PathBuf> {
+        let rendered = self.render(file, category, 1);
+        let mut dir = PathBuf::new();
+        for part in rendered.split('/').map(str::trim).filter(|p| !p.is_empty() && *p != ".") {
+            if part == ".." {
+                bail!("Template {:?} escapes the destination root", self.source);
+            }
+            dir.push(part);
+        }
+        Ok(dir)
+    }
+
+    /// Renders the template as a single sanitized file name; `counter` fills `{counter}`.
+    pub fn render_name(&self, file: &FileInfo, category: &str, counter: u32) -> Result<String> {
+        let name = sanitize_file_name(&self.render(file, category, counter));
+        if name.is_empty() {
+            bail!("Template {:?} renders an empty file name for {:?}", self.source, file.path);
+        }
+        Ok(name)
+    }
+
+    pub fn uses_counter(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|s| matches!(s, Segment::Placeholder { key, .. } if key == "counter"))
+    }
+
+    fn render(&self, file: &FileInfo, category: &str, counter: u32) -> String {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Placeholder { key, spec, filters } => {
+                    let mut value = match (key.as_str(), spec) {
+                        ("counter", Some(width)) => {
+                            format!("{:0width$}", counter, width = width.trim().parse().unwrap_or(0))
+                        }
+                        ("counter", None) => counter.to_string(),
+                        (_, Some(format)) => lookup(key, file, category)
+                            .and_then(|v| parse_date(&v))
+                            .map(|d| d.format(format).to_string())
+                            .unwrap_or_default(),
+                        (_, None) => lookup(key, file, category).unwrap_or_default(),
+                    };
+                    // Only the category may introduce folders; metadata values stay one component
+                    if key != "category" {
+                        value = value.replace(['/', '\\'], "-");
+                    }
+                    for filter in filters {
+                        value = filter.apply(value);
+                    }
+                    out.push_str(&value);
+                }
+            }
+        }
+        out
+    }
+}
+
+fn parse_placeholder(body: &str, source: &str) -> Result<Segment> {
+    let mut parts = body.split('|').map(str::trim);
+    let head = parts.next().unwrap_or_default();
+    // `{key:spec}`: a strftime format for dates, a zero-pad width for `counter`
+    let (key, spec) = match head.split_once(':') {
+        Some((key, spec)) => (key.trim(), Some(spec.to_string())),
+        None => (head, None),
+    };
+    if key.is_empty() {
+        bail!("Empty placeholder in template {:?}", source);
+    }
+    match (key, &spec) {
+        ("counter", Some(width)) => match width.trim().parse::<usize>() {
+            Ok(width) if width <= MAX_COUNTER_WIDTH => {}
+            _ => bail!("Counter width {:?} in template {:?} is not a number up to {}", width, source, MAX_COUNTER_WIDTH),
+        },
+        // chrono panics on an invalid format while rendering, so reject it here
+        (_, Some(format)) if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) => {
+            bail!("Invalid date format {:?} in template {:?}", format, source)
+        }
+        _ => {}
+    }
+    let filters = parts.map(|f| Filter::parse(f, source)).collect::<Result<Vec<_>>>()?;
+    Ok(Segment::Placeholder { key: key.to_string(), spec, filters })
+}
+
+impl Filter {
+    fn parse(spec: &str, source: &str) -> Result<Self> {
+        if let Some(arg) = spec.strip_prefix("default(").and_then(|s| s.strip_suffix(')')) {
+            return Ok(Filter::Default(arg.trim_matches(|c| c == '"' || c == '\'').to_string()));
+        }
+        match spec {
+            "lower" => Ok(Filter::Lower),
+            "upper" => Ok(Filter::Upper),
+            "slug" => Ok(Filter::Slug),
+            _ => bail!("Unknown filter {:?} in template {:?}", spec, source),
+        }
+    }
+
+    fn apply(&self, value: String) -> String {
+        match self {
+            Filter::Lower => value.to_lowercase(),
+            Filter::Upper => value.to_uppercase(),
+            Filter::Slug => slugify(&value),
+            Filter::Default(fallback) => {
+                if value.trim().is_empty() {
+                    fallback.clone()
+                } else {
+                    value
+                }
+            }
+        }
+    }
+}
+
+fn lookup(key: &str, file: &FileInfo, category: &str) -> Option<String> {
+    let modified = file.metadata.modified;
+    match key {
+        "category" => Some(category.to_string()),
+        "year" => modified.map(|d| d.format("%Y").to_string()),
+        "month" => modified.map(|d| d.format("%m").to_string()),
+        "day" => modified.map(|d| d.format("%d").to_string()),
+        "quarter" => modified.map(|d| format!("Q{}", (d.month() - 1) / 3 + 1)),
+        "modified" | "date" => modified.map(|d| d.to_rfc3339()),
+        "created" => file.metadata.created.map(|d| d.to_rfc3339()),
+        "ext" | "extension" => Some(file.metadata.extension.clone()),
+        "mime" => file.metadata.mime_type.split('/').next().map(str::to_string),
+        "size" | "size_bucket" => Some(size_bucket(file.metadata.size).to_string()),
+        "name" => Some(file.name.clone()),
+        "stem" => file.path.file_stem().map(|s| s.to_string_lossy().to_string()),
+        _ => lookup_extra(key, file),
+    }
+}
+
+/// `metadata.extra` lookup; `exif.camera_model` also finds a plain `camera_model` key.
+fn lookup_extra(key: &str, file: &FileInfo) -> Option<String> {
+    let extra = &file.metadata.extra;
+    extra
+        .get(key)
+        .or_else(|| key.rsplit_once('.').and_then(|(_, bare)| extra.get(bare)))
+        .cloned()
+}
+
+/// Parses the date formats that show up in file metadata (RFC 3339, EXIF, PDF).
+fn parse_date(value: &str) -> Option<NaiveDateTime> {
+    let value = value.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.with_timezone(&Local).naive_local());
+    }
+    // PDF dates look like D:20240131120000+01'00'; only the leading digits matter
+    let value = value.strip_prefix("D:").unwrap_or(value);
+    let formats = [("%Y-%m-%d %H:%M:%S", 19), ("%Y:%m:%d %H:%M:%S", 19), ("%Y%m%d%H%M%S", 14)];
+    for (format, len) in formats {
+        if let Some(date) = value.get(..len).and_then(|v| NaiveDateTime::parse_from_str(v, format).ok()) {
+            return Some(date);
+        }
+    }
+    value
+        .get(..10)
+        .and_then(|v| NaiveDate::parse_from_str(v, "%Y-%m-%d").ok())
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+}
+
+fn size_bucket(bytes: u64) -> &'static str {
+    const KB: u64 = 1024;
+    const MB: u64 = KB * 1024;
+    const GB: u64 = MB * 1024;
+
+    match bytes {
+        b if b < 100 * KB => "tiny",
+        b if b < 10 * MB => "small",
+        b if b < 100 * MB => "medium",
+        b if b < GB => "large",
+        _ => "huge",
+    }
+}
+
+pub fn slugify(value: &str) -> String {
+    let mut slug = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Makes `name` safe as a single file name on every platform: illegal and control
+/// characters become `_`, trailing dots and spaces go, and the stem is cut so the
+/// whole name fits in `MAX_FILE_NAME_BYTES` while keeping the extension.
+pub fn sanitize_file_name(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let cleaned = cleaned.trim().trim_end_matches(['.', ' ']);
+
+    if cleaned.len() <= MAX_FILE_NAME_BYTES {
+        return cleaned.to_string();
+    }
+    let (stem, ext) = match cleaned.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() < 16 => (stem, format!(".{}", ext)),
+        _ => (cleaned, String::new()),
+    };
+    let mut cut = MAX_FILE_NAME_BYTES - ext.len();
+    while !stem.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    format!("{}{}", stem[..cut].trim_end(), ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn photo() -> FileInfo {
+        let mut file = FileInfo::new(PathBuf::from("/in/IMG_001.jpg"));
+        file.metadata.extension = "jpg".to_string();
+        file.metadata.modified = Some(Local.with_ymd_and_hms(2024, 3, 9, 10, 0, 0).unwrap());
+        file.metadata.extra.insert("exif.camera_model".to_string(), "Canon EOS R5".to_string());
+        file
+    }
+
+    #[test]
+    fn rejects_malformed_templates() {
+        assert!(Template::parse("{category").is_err());
+        assert!(Template::parse("{}").is_err());
+        assert!(Template::parse("{name|shout}").is_err());
+        assert!(Template::parse("{category}/{modified:%Q}").is_err());
+        assert!(Template::parse("{modified:%Y-%}").is_err());
+        assert!(Template::parse("{counter:abc}").is_err());
+        assert!(Template::parse("{counter:99999}").is_err());
+    }
+
+    #[test]
+    fn renders_dates_counters_and_filters() {
+        let template = Template::parse("{modified:%Y-%m-%d}_{exif.camera_model|slug}_{counter:03}.{ext}").unwrap();
+        assert_eq!(template.render_name(&photo(), "Images", 7).unwrap(), "2024-03-09_canon-eos-r5_007.jpg");
+        assert!(template.uses_counter());
+    }
+
+    #[test]
+    fn missing_values_drop_folders_or_use_defaults() {
+        let template = Template::parse("{category}/{exif.lens}/{exif.lens|default(unknown)}/{year}").unwrap();
+        let dir = template.render_dir(&photo(), "Images").unwrap();
+        assert_eq!(dir, PathBuf::from("Images/unknown/2024"));
+        assert!(Template::parse("{category}/../x").unwrap().render_dir(&photo(), "Images").is_err());
+    }
+
+    #[test]
+    fn metadata_values_stay_one_component() {
+        let mut file = photo();
+        file.metadata.extra.insert("title".to_string(), "a/b\\c".to_string());
+        let template = Template::parse("{title}").unwrap();
+        assert_eq!(template.render_dir(&file, "Docs").unwrap(), PathBuf::from("a-b-c"));
+    }
+
+    #[test]
+    fn sanitizes_file_names() {
+        assert_eq!(sanitize_file_name("a<b>:c?.txt"), "a_b__c_.txt");
+        assert_eq!(sanitize_file_name("  report. . "), "report");
+        assert_eq!(sanitize_file_name("line\nbreak"), "line_break");
+
+        let long = format!("{}.jpeg", "é".repeat(MAX_FILE_NAME_BYTES));
+        let name = sanitize_file_name(&long);
+        assert!(name.len() <= MAX_FILE_NAME_BYTES);
+        assert!(name.ends_with(".jpeg"));
+        assert!(name.starts_with('é'));
+    }
+}
@@ -0,0 +1,311 @@
+use crate::organizer::metadata::hash_path;
+use crate::organizer::renamer::PlacementMode;
+use anyhow::{Context, Result, bail};
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+/// Files at least this big report copy progress on stderr
+const PROGRESS_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Moves `src` (a file or a whole directory) to `dst`. A plain rename when both are
+/// on the same filesystem; otherwise a streamed copy that is hash-verified before the
+/// source is removed.
+pub fn move_file(src: &Path, dst: &Path) -> Result<()> {
+    match fs::rename(src, dst) {
+        Ok(()) => Ok(()),
+        Err(e) if is_cross_device(&e) => {
+            log::info!("{:?} and {:?} are on different filesystems, copying", src, dst);
+            copy_entry(src, dst)?;
+            remove_entry(src)
+                .with_context(|| format!("Copied to {:?} but failed to remove {:?}", dst, src))
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to move {:?} to {:?}", src, dst)),
+    }
+}
+
+/// Puts `src` at `dst` the way `mode` says.
+pub fn place(mode: PlacementMode, src: &Path, dst: &Path) -> Result<()> {
+    match mode {
+        PlacementMode::Move => move_file(src, dst),
+        PlacementMode::Copy => copy_entry(src, dst),
+        PlacementMode::Hardlink => fs::hard_link(src, dst)
+            .with_context(|| format!("Failed to hardlink {:?} to {:?}", src, dst)),
+        PlacementMode::Symlink => symlink(src, dst)
+            .with_context(|| format!("Failed to symlink {:?} to {:?}", dst, src)),
+    }
+}
+
+/// What `duplicates --link` leaves in place of a redundant copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkKind {
+    /// Another name for the kept file; an edit through either name changes both
+    Hardlink,
+    /// A copy-on-write clone (Btrfs, XFS, APFS); shares blocks but edits stay separate
+    Reflink,
+    /// A symbolic link to the kept file's absolute path
+    Symlink,
+}
+
+impl LinkKind {
+    pub fn verb(self) -> &'static str {
+        match self {
+            LinkKind::Hardlink => "hardlink",
+            LinkKind::Reflink => "reflink",
+            LinkKind::Symlink => "symlink",
+        }
+    }
+}
+
+/// Swaps `path` for a link to `target`. The link is made under a temporary name and
+/// renamed over `path`, so `path` is always either the untouched original or the
+/// finished link; on failure the original is left as it was.
+pub fn replace_with_link(kind: LinkKind, target: &Path, path: &Path) -> Result<()> {
+    if kind != LinkKind::Symlink && !same_file_system(target, path)? {
+        bail!("{:?} and {:?} are on different filesystems", target, path);
+    }
+    let tmp = partial_path(path);
+    let result = match kind {
+        LinkKind::Hardlink => fs::hard_link(target, &tmp).map_err(anyhow::Error::from),
+        // A clone is a new inode, so it can keep the duplicate's own permissions and times
+        LinkKind::Reflink => reflink(target, &tmp).and_then(|()| copy_attributes(path, &tmp)),
+        LinkKind::Symlink => fs::canonicalize(target)
+            .and_then(|target| symlink(&target, &tmp))
+            .map_err(anyhow::Error::from),
+    }
+    .and_then(|()| Ok(fs::rename(&tmp, path)?));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.context(format!("Failed to {} {:?} to {:?}", kind.verb(), path, target)));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn same_file_system(a: &Path, b: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(fs::metadata(a)?.dev() == fs::metadata(b)?.dev())
+}
+
+#[cfg(windows)]
+fn same_file_system(_a: &Path, _b: &Path) -> Result<bool> {
+    // Hardlinks and clones across volumes fail on their own
+    Ok(true)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dst: &Path) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let source = File::open(src)?;
+    let target = fs::OpenOptions::new().write(true).create_new(true).open(dst)?;
+    // SAFETY: both descriptors are open for the duration of the call
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } != 0 {
+        bail!("the filesystem cannot clone files ({})", io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn reflink(src: &Path, dst: &Path) -> Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let source = CString::new(src.as_os_str().as_bytes())?;
+    let target = CString::new(dst.as_os_str().as_bytes())?;
+    // SAFETY: both pointers are valid NUL-terminated strings for the duration of the call
+    if unsafe { libc::clonefile(source.as_ptr(), target.as_ptr(), 0) } != 0 {
+        bail!("the filesystem cannot clone files ({})", io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_src: &Path, _dst: &Path) -> Result<()> {
+    bail!("reflinks are not supported on this platform")
+}
+
+/// Deletes a placed file, link or copied directory tree.
+pub fn remove_entry(path: &Path) -> Result<()> {
+    let attr = fs::symlink_metadata(path)?;
+    if attr.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(src, dst)
+}
+
+#[cfg(windows)]
+fn symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    if src.is_dir() {
+        std::os::windows::fs::symlink_dir(src, dst)
+    } else {
+        std::os::windows::fs::symlink_file(src, dst)
+    }
+}
+
+fn copy_entry(src: &Path, dst: &Path) -> Result<()> {
+    if fs::symlink_metadata(src)?.is_dir() {
+        copy_tree(src, dst)
+    } else {
+        copy_verified(src, dst).map(|_| ())
+    }
+}
+
+/// Copies a directory tree file by file (each verified), building it under a
+/// temporary name so `dst` only ever appears complete. Links are recreated as links.
+fn copy_tree(src: &Path, dst: &Path) -> Result<()> {
+    let tmp = partial_path(dst);
+    let result = (|| -> Result<()> {
+        for entry in walkdir::WalkDir::new(src) {
+            let entry = entry?;
+            let target = tmp.join(entry.path().strip_prefix(src)?);
+            let file_type = entry.file_type();
+            if file_type.is_dir() {
+                fs::create_dir_all(&target)?;
+            } else if file_type.is_symlink() {
+                symlink(&fs::read_link(entry.path())?, &target)?;
+            } else {
+                copy_verified(entry.path(), &target)?;
+            }
+        }
+        fs::set_permissions(&tmp, fs::metadata(src)?.permissions())?;
+        fs::rename(&tmp, dst)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&tmp);
+    }
+    result
+}
+
+#[cfg(unix)]
+fn is_cross_device(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EXDEV)
+}
+
+#[cfg(windows)]
+fn is_cross_device(e: &io::Error) -> bool {
+    // ERROR_NOT_SAME_DEVICE
+    e.raw_os_error() == Some(17)
+}
+
+/// Copies through a temporary name next to `dst`, so a crash never leaves a
+/// half-written file under the final name. Returns the blake3 hash of the content.
+pub fn copy_verified(src: &Path, dst: &Path) -> Result<String> {
+    let tmp = partial_path(dst);
+    let result = copy_to(src, &tmp).and_then(|hash| {
+        let written = hash_path(&tmp)?;
+        if written != hash {
+            bail!("Copy of {:?} does not match the source (hash mismatch)", src);
+        }
+        copy_attributes(src, &tmp)?;
+        fs::rename(&tmp, dst)?;
+        Ok(hash)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn partial_path(dst: &Path) -> PathBuf {
+    let name = dst.file_name().unwrap_or_default().to_string_lossy();
+    dst.with_file_name(format!(".{}.partial-{}", name, std::process::id()))
+}
+
+/// Deletes temporary copies of `dst` that a crashed run left behind. Returns how
+/// many were removed.
+pub fn remove_partials(dst: &Path) -> Result<usize> {
+    let (Some(parent), Some(name)) = (dst.parent(), dst.file_name()) else {
+        return Ok(0);
+    };
+    let Ok(entries) = fs::read_dir(parent) else {
+        return Ok(0);
+    };
+    let prefix = format!(".{}.partial-", name.to_string_lossy());
+    let mut removed = 0;
+    for entry in entries {
+        let path = entry?.path();
+        let leftover = path
+            .file_name()
+            .and_then(|n| n.to_string_lossy().strip_prefix(&prefix).map(|pid| pid.parse::<u32>().is_ok()))
+            .unwrap_or(false);
+        if leftover {
+            remove_entry(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Streams `src` into `dst`, hashing on the way.
+fn copy_to(src: &Path, dst: &Path) -> Result<String> {
+    let mut reader = BufReader::with_capacity(COPY_BUFFER_SIZE, File::open(src)?);
+    let mut writer = File::create(dst)?;
+    let total = reader.get_ref().metadata()?.len();
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let mut copied = 0u64;
+    let mut last_percent = 0;
+
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        writer.write_all(&buffer[..n])?;
+        copied += n as u64;
+
+        if total >= PROGRESS_THRESHOLD {
+            let percent = copied * 100 / total;
+            if percent >= last_percent + 5 {
+                eprint!("\rCopying {:?}: {}%", src.file_name().unwrap_or_default(), percent);
+                last_percent = percent;
+            }
+        }
+    }
+    if total >= PROGRESS_THRESHOLD {
+        eprintln!();
+    }
+    writer.sync_all()?;
+    Ok(hasher.finalize().to_string())
+}
+
+/// Carries permissions, access/modification times and (on unix) extended attributes
+/// over. Attributes the target filesystem refuses are skipped with a warning.
+fn copy_attributes(src: &Path, dst: &Path) -> Result<()> {
+    let attr = fs::metadata(src)?;
+    // Network shares (CIFS, many NAS) often refuse modes or times; the data still moved
+    if let Err(e) = fs::set_permissions(dst, attr.permissions()) {
+        log::warn!("Could not copy permissions to {:?}: {}", dst, e);
+    }
+    if let Err(e) = filetime::set_file_times(
+        dst,
+        FileTime::from_last_access_time(&attr),
+        FileTime::from_last_modification_time(&attr),
+    ) {
+        log::warn!("Could not copy timestamps to {:?}: {}", dst, e);
+    }
+
+    #[cfg(unix)]
+    if let Ok(names) = xattr::list(src) {
+        for name in names {
+            if let Ok(Some(value)) = xattr::get(src, &name) {
+                if let Err(e) = xattr::set(dst, &name, &value) {
+                    log::warn!("Could not copy xattr {:?} to {:?}: {}", name, dst, e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
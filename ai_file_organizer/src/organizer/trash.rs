@@ -0,0 +1,145 @@
+use anyhow::{Context, Result, bail};
+use chrono::Local;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Whether `trash` works here. Commands that may need it check this before they
+/// start, so they never stop halfway through a run.
+pub const AVAILABLE: bool = cfg!(all(unix, not(target_os = "macos")));
+
+/// Moves `path` into the freedesktop.org Trash: the home trash when it lives on the
+/// same filesystem, otherwise `$topdir/.Trash/$uid` or `$topdir/.Trash-$uid` on the
+/// file's own mount. Returns where the file now is, inside the trash's `files` folder.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn trash(path: &Path) -> Result<PathBuf> {
+    // Resolve the folder only; a symlink is trashed itself, not what it points at
+    let name = path.file_name().context("cannot trash a path without a file name")?;
+    let folder = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let path = fs::canonicalize(folder)?.join(name);
+    let (trash_dir, topdir) = trash_dir_for(&path)?;
+    let files = trash_dir.join("files");
+    fs::create_dir_all(&files)?;
+    fs::create_dir_all(trash_dir.join("info"))?;
+
+    let name = name.to_string_lossy().to_string();
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{}", ext)),
+        _ => (name.clone(), String::new()),
+    };
+    for n in 1.. {
+        let candidate = if n == 1 { name.clone() } else { format!("{}.{}{}", stem, n, ext) };
+        let location = files.join(&candidate);
+        if fs::symlink_metadata(&location).is_ok() {
+            continue;
+        }
+        match trash_as(&path, &location, topdir.as_deref()) {
+            Ok(()) => return Ok(location),
+            // Someone else took the name between our check and the info file
+            Err(e) if e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::AlreadyExists) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("the name counter is unbounded")
+}
+
+#[cfg(any(windows, target_os = "macos"))]
+pub fn trash(_path: &Path) -> Result<PathBuf> {
+    bail!("the freedesktop Trash is not available on this platform; use --permanent to delete")
+}
+
+/// Moves `path` to `location` inside a trash `files` folder and writes its
+/// `.trashinfo`. With a `topdir` (per-mount trash) the path is stored relative to
+/// it; an absolute path is valid in any trash.
+pub fn trash_as(path: &Path, location: &Path, topdir: Option<&Path>) -> Result<()> {
+    let info = info_path(location)?;
+    let recorded = topdir.and_then(|top| path.strip_prefix(top).ok()).unwrap_or(path);
+    let content = format!(
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        encode_path(recorded),
+        Local::now().format("%Y-%m-%dT%H:%M:%S")
+    );
+    // Creating the info file exclusively is what reserves the name
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&info)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, content.as_bytes()))?;
+    if let Err(e) = fs::rename(path, location) {
+        let _ = fs::remove_file(&info);
+        return Err(e).with_context(|| format!("Failed to move {:?} to the trash", path));
+    }
+    Ok(())
+}
+
+/// Takes a trashed file back out to `original` and drops its `.trashinfo`.
+pub fn restore(location: &Path, original: &Path) -> Result<()> {
+    if let Some(parent) = original.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(location, original)
+        .with_context(|| format!("Failed to restore {:?} from the trash", original))?;
+    let _ = fs::remove_file(info_path(location)?);
+    Ok(())
+}
+
+/// `<trash>/files/<name>` has its metadata in `<trash>/info/<name>.trashinfo`.
+fn info_path(location: &Path) -> Result<PathBuf> {
+    let name = location.file_name().context("trash entry without a name")?;
+    let trash_dir = location
+        .parent()
+        .and_then(Path::parent)
+        .context("trash entry outside a trash folder")?;
+    Ok(trash_dir.join("info").join(format!("{}.trashinfo", name.to_string_lossy())))
+}
+
+/// The trash folder for `path`, and the mount's top directory when it is not the home trash.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn trash_dir_for(path: &Path) -> Result<(PathBuf, Option<PathBuf>)> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let device = fs::symlink_metadata(path)?.dev();
+    if let Some(home_trash) = dirs::data_dir().map(|dir| dir.join("Trash")) {
+        // The home trash may not exist yet; its closest existing ancestor decides
+        let home_device = home_trash.ancestors().find_map(|dir| fs::metadata(dir).ok()).map(|attr| attr.dev());
+        if home_device == Some(device) {
+            return Ok((home_trash, None));
+        }
+    }
+
+    let topdir = path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| fs::metadata(dir).is_ok_and(|attr| attr.dev() == device))
+        .last()
+        .context("cannot find the mount point")?
+        .to_path_buf();
+    // SAFETY: getuid has no preconditions and cannot fail
+    let uid = unsafe { libc::getuid() };
+
+    // An admin-provided `.Trash` must be a real, sticky directory to be trusted
+    let shared = topdir.join(".Trash");
+    if fs::symlink_metadata(&shared).is_ok_and(|attr| attr.is_dir() && attr.permissions().mode() & 0o1000 != 0) {
+        return Ok((shared.join(uid.to_string()), Some(topdir)));
+    }
+    let own = topdir.join(format!(".Trash-{}", uid));
+    if !own.exists() {
+        fs::create_dir(&own).with_context(|| format!("Cannot create a trash folder at {:?}", own))?;
+        fs::set_permissions(&own, fs::Permissions::from_mode(0o700))?;
+    }
+    if fs::symlink_metadata(&own)?.file_type().is_symlink() {
+        bail!("refusing to use {:?}, it is a symlink", own);
+    }
+    Ok((own, Some(topdir)))
+}
+
+/// Percent-encodes a path for the `Path=` key, byte by byte as the spec asks.
+fn encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_encoded_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
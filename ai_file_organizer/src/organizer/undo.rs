@@ -1,25 +1,203 @@
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
-use anyhow::Result;
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, Local};
 use std::fs;
 use crate::constants::UNDO_HISTORY_FILE;
+use crate::organizer::metadata::hash_path;
+use crate::organizer::renamer::PlacementMode;
+use crate::organizer::transfer::{self, LinkKind};
+use crate::organizer::trash;
+use crate::organizer::utils::format_size;
+
+/// How a recorded file got to where it is, which decides how it is put back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UndoAction {
+    /// Renamed from `original_path` to `current_path`
+    #[default]
+    Move,
+    /// `original_path` was deleted because `current_path` holds identical content
+    RemovedDuplicate,
+    /// `current_path` is a copy; `original_path` was left alone
+    Copied,
+    /// `current_path` is a hardlink to `original_path`
+    Hardlinked,
+    /// `current_path` is a symlink pointing at `original_path`
+    Symlinked,
+    /// `original_path` was a duplicate and now links to the kept `current_path`
+    LinkedDuplicate(LinkKind),
+    /// `original_path` was moved to the trash, where it is `current_path`
+    Trashed,
+}
+
+impl From<PlacementMode> for UndoAction {
+    fn from(mode: PlacementMode) -> Self {
+        match mode {
+            PlacementMode::Move => UndoAction::Move,
+            PlacementMode::Copy => UndoAction::Copied,
+            PlacementMode::Hardlink => UndoAction::Hardlinked,
+            PlacementMode::Symlink => UndoAction::Symlinked,
+        }
+    }
+}
+
+impl UndoAction {
+    /// The placement mode that created the entry, for the actions that left the original in place.
+    fn placement(self) -> Option<PlacementMode> {
+        match self {
+            UndoAction::Copied => Some(PlacementMode::Copy),
+            UndoAction::Hardlinked => Some(PlacementMode::Hardlink),
+            UndoAction::Symlinked => Some(PlacementMode::Symlink),
+            UndoAction::Move | UndoAction::RemovedDuplicate | UndoAction::LinkedDuplicate(_) | UndoAction::Trashed => None,
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UndoEntry {
+    #[serde(default)]
+    pub action: UndoAction,
     pub original_path: PathBuf,
     pub current_path: PathBuf,
+    /// blake3 hash of the file right after it was moved
+    #[serde(default)]
+    pub hash: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub undone: bool,
+    /// Original path of the group's primary file, for companions moved together
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UndoSession {
+    pub id: u64,
+    pub timestamp: DateTime<Local>,
+    pub command: String,
+    pub file_count: usize,
+    pub bytes_moved: u64,
+    pub entries: Vec<UndoEntry>,
+    /// When entries of this session were last rolled back, used to pick the redo target
+    #[serde(default)]
+    pub undone_at: Option<DateTime<Local>>,
+}
+
+impl UndoSession {
+    pub fn status(&self) -> &'static str {
+        let undone = self.entries.iter().filter(|e| e.undone).count();
+        if undone == 0 {
+            "applied"
+        } else if undone == self.entries.len() {
+            "undone"
+        } else {
+            "partial"
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct UndoHistory {
-    pub entries: Vec<UndoEntry>,
+    pub sessions: Vec<UndoSession>,
+}
+
+/// Single-run format written before sessions existed
+#[derive(Deserialize)]
+struct LegacyHistory {
+    entries: Vec<UndoEntry>,
+}
+
+impl UndoHistory {
+    /// Opens a new numbered session and returns its id.
+    pub fn begin_session(&mut self, command: String) -> u64 {
+        let id = self.sessions.iter().map(|s| s.id).max().unwrap_or(0) + 1;
+        self.sessions.push(UndoSession {
+            id,
+            timestamp: Local::now(),
+            command,
+            file_count: 0,
+            bytes_moved: 0,
+            entries: Vec::new(),
+            undone_at: None,
+        });
+        id
+    }
+
+    /// Records a completed move and flushes the history to disk straight away,
+    /// so a run that dies halfway can still be rolled back.
+    pub fn record(
+        &mut self,
+        root: &Path,
+        session_id: u64,
+        action: UndoAction,
+        original_path: PathBuf,
+        current_path: PathBuf,
+        group: Option<PathBuf>,
+    ) -> Result<()> {
+        let size = fs::metadata(&current_path).map(|m| m.len()).unwrap_or(0);
+        let hash = hash_path(&current_path).unwrap_or_default();
+        let session = self.session_mut(session_id)?;
+        session.file_count += 1;
+        session.bytes_moved += size;
+        session.entries.push(UndoEntry { action, original_path, current_path, hash, size, undone: false, group });
+        save_history(self, root)
+    }
+
+    /// Whether any session records the operation from `original_path` to `current_path`.
+    pub fn contains_move(&self, original_path: &Path, current_path: &Path) -> bool {
+        self.sessions.iter().any(|s| {
+            s.entries
+                .iter()
+                .any(|e| e.original_path == original_path && e.current_path == current_path)
+        })
+    }
+
+    /// Flags the operation from `original_path` to `current_path` as rolled back by
+    /// something other than `undo` (e.g. recovery), so it is not restored twice.
+    pub fn mark_undone(&mut self, original_path: &Path, current_path: &Path) {
+        for session in self.sessions.iter_mut().rev() {
+            if let Some(entry) = session
+                .entries
+                .iter_mut()
+                .rev()
+                .find(|e| !e.undone && e.original_path == original_path && e.current_path == current_path)
+            {
+                entry.undone = true;
+                session.undone_at = Some(Local::now());
+                return;
+            }
+        }
+    }
+
+    /// Where the file last moved from `original_path` to the trash is now, while it
+    /// is still there.
+    pub fn trashed_from(&self, original_path: &Path) -> Option<PathBuf> {
+        self.sessions
+            .iter()
+            .rev()
+            .flat_map(|s| s.entries.iter().rev())
+            .find(|e| !e.undone && e.action == UndoAction::Trashed && e.original_path == original_path)
+            .map(|e| e.current_path.clone())
+            .filter(|location| fs::symlink_metadata(location).is_ok())
+    }
+
+    fn session_mut(&mut self, id: u64) -> Result<&mut UndoSession> {
+        self.sessions
+            .iter_mut()
+            .find(|s| s.id == id)
+            .ok_or_else(|| anyhow!("No undo session with id {}", id))
+    }
 }
 
-#[allow(dead_code)]
 pub fn save_history(history: &UndoHistory, root: &Path) -> Result<()> {
     let path = root.join(UNDO_HISTORY_FILE);
+    let tmp = path.with_extension("json.tmp");
     let content = serde_json::to_string_pretty(history)?;
-    fs::write(path, content)?;
+    // Write then rename so a crash mid-write never leaves a truncated journal
+    fs::write(&tmp, content)?;
+    fs::rename(tmp, path)?;
     Ok(())
 }
 
@@ -29,25 +207,479 @@ pub fn load_history(root: &Path) -> Result<UndoHistory> {
         return Ok(UndoHistory::default());
     }
     let content = fs::read_to_string(path)?;
-    let history = serde_json::from_str(&content)?;
+    if let Ok(history) = serde_json::from_str(&content) {
+        return Ok(history);
+    }
+
+    let legacy: LegacyHistory = serde_json::from_str(&content)?;
+    let mut history = UndoHistory::default();
+    let id = history.begin_session("(imported from single-run history)".to_string());
+    let session = history.session_mut(id)?;
+    session.file_count = legacy.entries.len();
+    session.entries = legacy.entries;
     Ok(history)
 }
 
-pub fn undo(root: &Path) -> Result<()> {
+pub fn list(root: &Path) -> Result<()> {
     let history = load_history(root)?;
-    for entry in history.entries.iter().rev() {
-        if entry.current_path.exists() {
-            if let Some(parent) = entry.original_path.parent() {
-                fs::create_dir_all(parent)?;
+    if history.sessions.is_empty() {
+        println!("No undo history in {:?}", root);
+        return Ok(());
+    }
+    for session in &history.sessions {
+        println!(
+            "#{:<4} {}  {:<8} {:>5} files  {:>10}  {}",
+            session.id,
+            session.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            session.status(),
+            session.file_count,
+            format_size(session.bytes_moved),
+            session.command
+        );
+    }
+    Ok(())
+}
+
+/// Rolls back a session, defaulting to the most recent one that still has applied moves.
+pub fn undo(root: &Path, session_id: Option<u64>, force: bool) -> Result<()> {
+    let mut history = load_history(root)?;
+    let id = match session_id {
+        Some(id) => id,
+        None => history
+            .sessions
+            .iter()
+            .rev()
+            .find(|s| s.entries.iter().any(|e| !e.undone))
+            .map(|s| s.id)
+            .ok_or_else(|| anyhow!("Nothing to undo in {:?}", root))?,
+    };
+
+    let session = history.session_mut(id)?;
+    let mut refusals = Refusals::default();
+    for entry in session.entries.iter_mut().rev().filter(|e| !e.undone) {
+        match restore(entry, force) {
+            Ok(()) => entry.undone = true,
+            Err(e) => refusals.record(&entry.current_path, "restore", e),
+        }
+    }
+    session.undone_at = Some(Local::now());
+    save_history(&history, root)?;
+    refusals.finish("restored")
+}
+
+/// Rolls back the most recent recorded move of a single file, matched by either its
+/// original or its current location. Companions moved with it come back too.
+pub fn undo_file(root: &Path, file: &Path, force: bool) -> Result<()> {
+    let mut history = load_history(root)?;
+    // The original location usually no longer exists, so it cannot be canonicalized
+    let cwd = std::env::current_dir()?;
+    let mut candidates = vec![absolute(file, &cwd), absolute(file, &absolute(root, &cwd))];
+    candidates.extend(fs::canonicalize(file).ok());
+    let names = |e: &UndoEntry| candidates.iter().any(|c| *c == e.current_path || *c == e.original_path);
+
+    let session = history
+        .sessions
+        .iter_mut()
+        .rev()
+        .find(|s| s.entries.iter().any(|e| !e.undone && names(e)))
+        .ok_or_else(|| anyhow!("No recorded move for {:?}", file))?;
+    let index = session
+        .entries
+        .iter()
+        .rposition(|e| !e.undone && names(e))
+        .expect("session was selected for containing this entry");
+
+    let group = session.entries[index].group.clone();
+    let mut refusals = Refusals::default();
+    for (i, entry) in session.entries.iter_mut().enumerate().rev() {
+        let selected = i == index || (group.is_some() && entry.group == group);
+        if !selected || entry.undone {
+            continue;
+        }
+        match restore(entry, force) {
+            Ok(()) => entry.undone = true,
+            Err(e) => refusals.record(&entry.current_path, "restore", e),
+        }
+    }
+    session.undone_at = Some(Local::now());
+    save_history(&history, root)?;
+    refusals.finish("restored")
+}
+
+/// `path` made absolute against `base` and normalized without touching the disk.
+fn absolute(path: &Path, base: &Path) -> PathBuf {
+    let mut absolute = PathBuf::new();
+    for component in base.join(path).components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                absolute.pop();
             }
-            fs::rename(&entry.current_path, &entry.original_path)?;
+            other => absolute.push(other),
+        }
+    }
+    absolute
+}
+
+/// Re-applies a rolled back session, defaulting to the one undone most recently.
+pub fn redo(root: &Path, session_id: Option<u64>, force: bool) -> Result<()> {
+    let mut history = load_history(root)?;
+    let id = match session_id {
+        Some(id) => id,
+        None => history
+            .sessions
+            .iter()
+            .filter(|s| s.entries.iter().any(|e| e.undone))
+            .max_by_key(|s| s.undone_at)
+            .map(|s| s.id)
+            .ok_or_else(|| anyhow!("Nothing to redo in {:?}", root))?,
+    };
+
+    let session = history.session_mut(id)?;
+    let mut refusals = Refusals::default();
+    for entry in session.entries.iter_mut().filter(|e| e.undone) {
+        match reapply(entry, force) {
+            Ok(()) => entry.undone = false,
+            Err(e) => refusals.record(&entry.original_path, "redo", e),
+        }
+    }
+    if session.entries.iter().all(|e| !e.undone) {
+        session.undone_at = None;
+    }
+    save_history(&history, root)?;
+    refusals.finish("moved again")
+}
+
+/// Counts refused entries, and how many of them `--force` would have let through.
+#[derive(Default)]
+struct Refusals {
+    refused: usize,
+    changed: usize,
+}
+
+impl Refusals {
+    fn record(&mut self, path: &Path, verb: &str, error: anyhow::Error) {
+        eprintln!("Refusing to {} {:?}: {}", verb, path, error);
+        self.refused += 1;
+        if error.is::<ContentChanged>() {
+            self.changed += 1;
+        }
+    }
+
+    fn finish(self, done: &str) -> Result<()> {
+        match (self.refused, self.changed) {
+            (0, _) => Ok(()),
+            (refused, 0) => bail!("{} file(s) were not {}", refused, done),
+            (refused, changed) => bail!(
+                "{} file(s) were not {}; {} of them changed since and can be overridden with --force",
+                refused,
+                done,
+                changed
+            ),
+        }
+    }
+}
+
+/// The refusal `--force` overrides; every other refusal protects data it cannot.
+#[derive(Debug)]
+struct ContentChanged;
+
+impl std::fmt::Display for ContentChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "content changed since it was moved (hash mismatch)")
+    }
+}
+
+impl std::error::Error for ContentChanged {}
+
+fn restore(entry: &UndoEntry, force: bool) -> Result<()> {
+    if entry.action.placement().is_some() {
+        return remove_placed(entry, force);
+    }
+    if let UndoAction::LinkedDuplicate(kind) = entry.action {
+        return unlink_duplicate(entry, kind, force);
+    }
+    if !entry.current_path.exists() {
+        bail!("it is gone from where it was moved, nothing to put back");
+    }
+    if entry.original_path.exists() {
+        bail!("original location {:?} is occupied", entry.original_path);
+    }
+    check_unchanged(&entry.current_path, &entry.hash, force)?;
+
+    if let Some(parent) = entry.original_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match entry.action {
+        UndoAction::Move => {
+            transfer::move_file(&entry.current_path, &entry.original_path)?;
             println!("Restored: {:?} -> {:?}", entry.current_path, entry.original_path);
         }
+        UndoAction::RemovedDuplicate => {
+            fs::copy(&entry.current_path, &entry.original_path)?;
+            println!("Restored duplicate: {:?} (copied from {:?})", entry.original_path, entry.current_path);
+        }
+        UndoAction::Trashed => {
+            trash::restore(&entry.current_path, &entry.original_path)?;
+            println!("Restored from trash: {:?}", entry.original_path);
+        }
+        UndoAction::Copied | UndoAction::Hardlinked | UndoAction::Symlinked | UndoAction::LinkedDuplicate(_) => {
+            unreachable!("handled above")
+        }
     }
-    // Clear history after undo
-    let path = root.join(UNDO_HISTORY_FILE);
-    if path.exists() {
-        fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Turns a linked duplicate back into an independent copy of the kept file.
+fn unlink_duplicate(entry: &UndoEntry, kind: LinkKind, force: bool) -> Result<()> {
+    if !entry.current_path.exists() {
+        bail!("kept file {:?} no longer exists", entry.current_path);
+    }
+    check_unchanged(&entry.current_path, &entry.hash, force)?;
+    let link = fs::symlink_metadata(&entry.original_path).map_err(|_| anyhow!("link no longer exists"))?;
+    let linked = match kind {
+        LinkKind::Symlink => {
+            link.file_type().is_symlink()
+                && fs::canonicalize(&entry.original_path).ok() == fs::canonicalize(&entry.current_path).ok()
+        }
+        LinkKind::Hardlink | LinkKind::Reflink => hash_path(&entry.original_path)? == entry.hash,
+    };
+    if !linked && !force {
+        bail!("{:?} is no longer a {} to {:?}", entry.original_path, kind.verb(), entry.current_path);
+    }
+    // Copied under a temporary name and renamed over the link
+    transfer::copy_verified(&entry.current_path, &entry.original_path)?;
+    println!("Restored duplicate: {:?} (copied from {:?})", entry.original_path, entry.current_path);
+    Ok(())
+}
+
+/// Undoes a copy or link by deleting what was placed; the original never moved.
+fn remove_placed(entry: &UndoEntry, force: bool) -> Result<()> {
+    let placed = fs::symlink_metadata(&entry.current_path).map_err(|_| anyhow!("it is already gone, nothing to remove"))?;
+    if entry.action == UndoAction::Symlinked {
+        if !placed.file_type().is_symlink() || fs::read_link(&entry.current_path)? != entry.original_path {
+            bail!("{:?} is no longer a link to {:?}", entry.current_path, entry.original_path);
+        }
+    } else {
+        // Deleting the only remaining copy would lose the file
+        if !entry.original_path.exists() {
+            bail!("original {:?} is gone, keeping the copy", entry.original_path);
+        }
+        check_unchanged(&entry.current_path, &entry.hash, force)?;
+    }
+    transfer::remove_entry(&entry.current_path)?;
+    println!("Removed {:?} (placed from {:?})", entry.current_path, entry.original_path);
+    Ok(())
+}
+
+fn reapply(entry: &UndoEntry, force: bool) -> Result<()> {
+    if !entry.original_path.exists() {
+        bail!("it is gone from where it was restored, nothing to move again");
+    }
+    check_unchanged(&entry.original_path, &entry.hash, force)?;
+
+    match entry.action {
+        UndoAction::Move => {
+            if entry.current_path.exists() {
+                bail!("destination {:?} is occupied", entry.current_path);
+            }
+            if let Some(parent) = entry.current_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            transfer::move_file(&entry.original_path, &entry.current_path)?;
+            println!("Moved again: {:?} -> {:?}", entry.original_path, entry.current_path);
+        }
+        UndoAction::RemovedDuplicate => {
+            check_unchanged(&entry.current_path, &entry.hash, false)
+                .map_err(|_| anyhow!("{:?} no longer holds the same content", entry.current_path))?;
+            fs::remove_file(&entry.original_path)?;
+            println!("Removed duplicate again: {:?}", entry.original_path);
+        }
+        UndoAction::Trashed => {
+            if fs::symlink_metadata(&entry.current_path).is_ok() {
+                bail!("trash entry {:?} is occupied", entry.current_path);
+            }
+            trash::trash_as(&entry.original_path, &entry.current_path, None)?;
+            println!("Moved to trash again: {:?}", entry.original_path);
+        }
+        UndoAction::LinkedDuplicate(kind) => {
+            check_unchanged(&entry.current_path, &entry.hash, false)
+                .map_err(|_| anyhow!("{:?} no longer holds the same content", entry.current_path))?;
+            transfer::replace_with_link(kind, &entry.current_path, &entry.original_path)?;
+            println!("Linked duplicate again ({}): {:?} -> {:?}", kind.verb(), entry.original_path, entry.current_path);
+        }
+        UndoAction::Copied | UndoAction::Hardlinked | UndoAction::Symlinked => {
+            if fs::symlink_metadata(&entry.current_path).is_ok() {
+                bail!("destination {:?} is occupied", entry.current_path);
+            }
+            if let Some(parent) = entry.current_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mode = entry.action.placement().expect("copy and link actions have a placement");
+            transfer::place(mode, &entry.original_path, &entry.current_path)?;
+            println!("Placed again ({}): {:?} -> {:?}", mode.verb(), entry.original_path, entry.current_path);
+        }
     }
     Ok(())
 }
+
+fn check_unchanged(path: &Path, recorded: &str, force: bool) -> Result<()> {
+    if recorded.is_empty() {
+        return Ok(());
+    }
+    let current = hash_path(path)?;
+    if current != recorded {
+        if !force {
+            return Err(ContentChanged.into());
+        }
+        log::warn!("{:?} changed since it was moved; restoring anyway (--force)", path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root() -> (tempfile::TempDir, PathBuf) {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(tmp.path()).unwrap();
+        (tmp, root)
+    }
+
+    /// Creates `from`, moves it to `to` and journals the move in `session`.
+    fn moved(root: &Path, history: &mut UndoHistory, session: u64, from: &str, to: &str, group: Option<&str>) {
+        let (from, to) = (root.join(from), root.join(to));
+        fs::create_dir_all(to.parent().unwrap()).unwrap();
+        fs::write(&from, from.file_name().unwrap().to_string_lossy().as_bytes()).unwrap();
+        fs::rename(&from, &to).unwrap();
+        history.record(root, session, UndoAction::Move, from, to, group.map(|g| root.join(g))).unwrap();
+    }
+
+    #[test]
+    fn undo_takes_the_latest_session_first() {
+        let (_tmp, root) = root();
+        let mut history = UndoHistory::default();
+        let first = history.begin_session("organize".to_string());
+        moved(&root, &mut history, first, "a.txt", "Docs/a.txt", None);
+        let second = history.begin_session("organize".to_string());
+        moved(&root, &mut history, second, "b.txt", "Docs/b.txt", None);
+
+        undo(&root, None, false).unwrap();
+        assert!(root.join("b.txt").exists() && !root.join("a.txt").exists());
+        undo(&root, None, false).unwrap();
+        assert!(root.join("a.txt").exists());
+        assert!(undo(&root, None, false).is_err());
+
+        let history = load_history(&root).unwrap();
+        assert!(history.sessions.iter().all(|s| s.status() == "undone"));
+    }
+
+    #[test]
+    fn undo_by_session_id() {
+        let (_tmp, root) = root();
+        let mut history = UndoHistory::default();
+        let first = history.begin_session("organize".to_string());
+        moved(&root, &mut history, first, "a.txt", "Docs/a.txt", None);
+        let second = history.begin_session("organize".to_string());
+        moved(&root, &mut history, second, "b.txt", "Docs/b.txt", None);
+
+        undo(&root, Some(first), false).unwrap();
+        assert!(root.join("a.txt").exists());
+        assert!(root.join("Docs/b.txt").exists());
+        assert!(undo(&root, Some(9), false).is_err());
+    }
+
+    #[test]
+    fn undo_file_restores_one_file_by_either_path() {
+        let (_tmp, root) = root();
+        let mut history = UndoHistory::default();
+        let session = history.begin_session("organize".to_string());
+        moved(&root, &mut history, session, "a.txt", "Docs/a.txt", None);
+        moved(&root, &mut history, session, "b.txt", "Docs/b.txt", None);
+
+        // The original path no longer exists and is given relative to the root
+        undo_file(&root, Path::new("a.txt"), false).unwrap();
+        assert!(root.join("a.txt").exists());
+        assert!(root.join("Docs/b.txt").exists());
+
+        undo_file(&root, &root.join("Docs/./b.txt"), false).unwrap();
+        assert!(root.join("b.txt").exists());
+        assert!(undo_file(&root, Path::new("c.txt"), false).is_err());
+    }
+
+    #[test]
+    fn undo_file_brings_back_the_whole_group() {
+        let (_tmp, root) = root();
+        let mut history = UndoHistory::default();
+        let session = history.begin_session("organize".to_string());
+        moved(&root, &mut history, session, "IMG_1.CR2", "Photos/IMG_1.CR2", Some("IMG_1.CR2"));
+        moved(&root, &mut history, session, "IMG_1.xmp", "Photos/IMG_1.xmp", Some("IMG_1.CR2"));
+        moved(&root, &mut history, session, "other.txt", "Docs/other.txt", None);
+
+        undo_file(&root, &root.join("Photos/IMG_1.xmp"), false).unwrap();
+        assert!(root.join("IMG_1.CR2").exists() && root.join("IMG_1.xmp").exists());
+        assert!(root.join("Docs/other.txt").exists());
+    }
+
+    #[test]
+    fn redo_reapplies_what_undo_restored() {
+        let (_tmp, root) = root();
+        let mut history = UndoHistory::default();
+        let session = history.begin_session("organize".to_string());
+        moved(&root, &mut history, session, "a.txt", "Docs/a.txt", None);
+
+        undo(&root, None, false).unwrap();
+        redo(&root, None, false).unwrap();
+        assert!(root.join("Docs/a.txt").exists() && !root.join("a.txt").exists());
+        let history = load_history(&root).unwrap();
+        assert_eq!(history.sessions[0].status(), "applied");
+        assert!(redo(&root, None, false).is_err());
+    }
+
+    #[test]
+    fn changed_files_need_force() {
+        let (_tmp, root) = root();
+        let mut history = UndoHistory::default();
+        let session = history.begin_session("organize".to_string());
+        moved(&root, &mut history, session, "a.txt", "Docs/a.txt", None);
+        fs::write(root.join("Docs/a.txt"), "edited since").unwrap();
+
+        let refusal = undo(&root, None, false).unwrap_err().to_string();
+        assert!(refusal.contains("--force"), "{}", refusal);
+        assert!(root.join("Docs/a.txt").exists());
+
+        undo(&root, None, true).unwrap();
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "edited since");
+    }
+
+    #[test]
+    fn missing_files_are_not_blamed_on_force() {
+        let (_tmp, root) = root();
+        let mut history = UndoHistory::default();
+        let session = history.begin_session("organize".to_string());
+        moved(&root, &mut history, session, "a.txt", "Docs/a.txt", None);
+        fs::remove_file(root.join("Docs/a.txt")).unwrap();
+
+        let refusal = undo(&root, None, false).unwrap_err().to_string();
+        assert!(!refusal.contains("--force"), "{}", refusal);
+    }
+
+    #[test]
+    fn legacy_history_is_imported_as_one_session() {
+        let (_tmp, root) = root();
+        fs::create_dir(root.join("Docs")).unwrap();
+        fs::write(root.join("Docs/a.txt"), "a").unwrap();
+        let legacy = serde_json::json!({
+            "entries": [{ "original_path": root.join("a.txt"), "current_path": root.join("Docs/a.txt") }]
+        });
+        fs::write(root.join(UNDO_HISTORY_FILE), legacy.to_string()).unwrap();
+
+        let history = load_history(&root).unwrap();
+        assert_eq!(history.sessions.len(), 1);
+        assert_eq!(history.sessions[0].entries[0].action, UndoAction::Move);
+
+        undo(&root, None, false).unwrap();
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "a");
+    }
+}
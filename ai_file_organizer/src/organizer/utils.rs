@@ -1,4 +1,22 @@
+use anyhow::{Result, anyhow, bail};
+use std::io::{self, IsTerminal};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Parses fractions like `0.9` or `90%` into 0.0..=1.0.
+pub fn parse_fraction(input: &str) -> Result<f64> {
+    let input = input.trim();
+    let (number, scale) = match input.strip_suffix('%') {
+        Some(percent) => (percent.trim(), 100.0),
+        None => (input, 1.0),
+    };
+    let value = number.parse::<f64>().map_err(|_| anyhow!("Invalid fraction {:?}", input))? / scale;
+    if !(0.0..=1.0).contains(&value) {
+        bail!("{:?} is not between 0 and 1 (or 0% and 100%)", input);
+    }
+    Ok(value)
+}
 
 #[allow(dead_code)]
 pub fn is_hidden(path: &Path) -> bool {
@@ -7,7 +25,17 @@ pub fn is_hidden(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
-#[allow(dead_code)]
+/// Bytes held by the files below `dir`; symlinks are not followed.
+pub fn dir_size(dir: &Path) -> u64 {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|attr| attr.len())
+        .sum()
+}
+
 pub fn format_size(bytes: u64) -> String {
     const KB: u64 = 1024;
     const MB: u64 = KB * 1024;
@@ -24,6 +52,51 @@ pub fn format_size(bytes: u64) -> String {
     }
 }
 
+/// Parses sizes like `100M`, `1.5G`, `512k` or `2048` (bytes). Units are binary,
+/// matching `format_size`; `MB`, `MiB` and `M` all mean 1024 * 1024.
+pub fn parse_size(input: &str) -> Result<u64> {
+    let input = input.trim();
+    let split = input.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let number: f64 = number.parse().map_err(|_| anyhow!("Invalid size {:?}", input))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => bail!("Unknown size unit {:?} in {:?}", unit, input),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Longest age `parse_age` accepts
+const MAX_AGE_YEARS: u64 = 1000;
+
+/// Parses ages like `30d`, `12h`, `2w`, `6mo` or `1y`. A bare number is days.
+pub fn parse_age(input: &str) -> Result<Duration> {
+    let input = input.trim();
+    let split = input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let number: u64 = number.parse().map_err(|_| anyhow!("Invalid age {:?}", input))?;
+    const DAY: u64 = 24 * 60 * 60;
+    let seconds = match unit.trim().to_ascii_lowercase().as_str() {
+        "s" => 1,
+        "min" | "m" => 60,
+        "h" => 60 * 60,
+        "" | "d" => DAY,
+        "w" => 7 * DAY,
+        "mo" => 30 * DAY,
+        "y" => 365 * DAY,
+        _ => bail!("Unknown age unit {:?} in {:?} (use s, min, h, d, w, mo or y)", unit, input),
+    };
+    // Bounded so `now - age` always stays a representable date
+    match number.checked_mul(seconds) {
+        Some(age) if age <= MAX_AGE_YEARS * 365 * DAY => Ok(Duration::from_secs(age)),
+        _ => bail!("Age {:?} is longer than {} years", input, MAX_AGE_YEARS),
+    }
+}
+
 #[allow(dead_code)]
 pub fn is_screenshot(name: &str) -> bool {
     let re = regex::Regex::new(r"(?i)screenshot|scrnshot|capture").unwrap();
@@ -40,8 +113,10 @@ pub struct Stats {
 
 #[allow(dead_code)]
 pub fn calculate_stats(files: &[crate::models::FileInfo]) -> Stats {
-    let mut stats = Stats::default();
-    stats.total_files = files.len();
+    let mut stats = Stats {
+        total_files: files.len(),
+        ..Default::default()
+    };
     for file in files {
         stats.total_size += file.metadata.size;
         if let Some(cat) = &file.category {
@@ -50,3 +125,65 @@ pub fn calculate_stats(files: &[crate::models::FileInfo]) -> Stats {
     }
     stats
 }
+
+/// A `done/total` counter on stderr for one stage, shared by worker threads.
+pub struct Progress<'a> {
+    stage: &'a str,
+    total: usize,
+    done: AtomicUsize,
+    live: bool,
+}
+
+impl<'a> Progress<'a> {
+    pub fn new(stage: &'a str, total: usize) -> Self {
+        if !stage.is_empty() {
+            eprintln!("{}", stage);
+        }
+        // Redrawing only makes sense on a terminal, and only when there is real work
+        let live = !stage.is_empty() && total >= 100 && io::stderr().is_terminal();
+        Self { stage, total, done: AtomicUsize::new(0), live }
+    }
+
+    pub fn tick(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.live && (done.is_multiple_of((self.total / 100).max(1)) || done == self.total) {
+            eprint!("\r  {}: {}/{}", self.stage, done, self.total);
+        }
+    }
+
+    pub fn finish(&self) {
+        if self.live {
+            eprintln!();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("10k").unwrap(), 10 * 1024);
+        assert_eq!(parse_size("1.5 MB").unwrap(), 3 * 512 * 1024);
+        assert_eq!(parse_size("2GiB").unwrap(), 2 << 30);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("10 parsecs").is_err());
+    }
+
+    #[test]
+    fn parses_ages() {
+        assert_eq!(parse_age("30").unwrap(), Duration::from_secs(30 * 24 * 60 * 60));
+        assert_eq!(parse_age("12h").unwrap(), Duration::from_secs(12 * 60 * 60));
+        assert_eq!(parse_age("5min").unwrap(), Duration::from_secs(5 * 60));
+        assert_eq!(parse_age("2w").unwrap(), parse_age("14d").unwrap());
+        assert!(parse_age("1000y").is_ok());
+        assert!(parse_age("1001y").is_err());
+        assert!(parse_age("99999999999999y").is_err());
+        assert!(parse_age("99999999999999999999").is_err());
+        assert!(parse_age("-1d").is_err());
+        assert!(parse_age("3 fortnights").is_err());
+    }
+}
@@ -0,0 +1,440 @@
+use crate::constants::INTENT_LOG_FILE;
+use crate::organizer::metadata::hash_path;
+use crate::organizer::plan::{PlanAction, PlannedMove};
+use crate::organizer::renamer::{PlacementMode, unique_path};
+use crate::organizer::{plan, transfer, trash};
+use crate::organizer::undo::{self, UndoAction};
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OpState {
+    Planned,
+    Started,
+    Done,
+}
+
+/// One line of the write-ahead log. `destination` is the intended path while planned
+/// and the collision-free final path once started.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntentRecord {
+    pub seq: usize,
+    pub state: OpState,
+    #[serde(default)]
+    pub action: PlanAction,
+    #[serde(default)]
+    pub mode: PlacementMode,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoverAction {
+    /// Carry out the operations the interrupted run did not get to
+    Finish,
+    /// Move everything the interrupted run already moved back to where it was
+    Rollback,
+}
+
+/// Append-only intent log for one organize run, fsynced after every record.
+pub struct IntentLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl IntentLog {
+    pub fn create(root: &Path) -> Result<Self> {
+        let path = root.join(INTENT_LOG_FILE);
+        let file = OpenOptions::new().create(true).truncate(true).write(true).open(&path)?;
+        Ok(Self { path, file })
+    }
+
+    pub fn planned(&mut self, seq: usize, op: &PlannedMove) -> Result<()> {
+        self.append(seq, OpState::Planned, op)
+    }
+
+    pub fn started(&mut self, seq: usize, op: &PlannedMove) -> Result<()> {
+        self.append(seq, OpState::Started, op)
+    }
+
+    pub fn done(&mut self, seq: usize, op: &PlannedMove) -> Result<()> {
+        self.append(seq, OpState::Done, op)
+    }
+
+    /// Marks the run as complete by removing the log.
+    pub fn commit(self) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(self.path)?;
+        Ok(())
+    }
+
+    fn append(&mut self, seq: usize, state: OpState, op: &PlannedMove) -> Result<()> {
+        let record = IntentRecord {
+            seq,
+            state,
+            action: op.action,
+            mode: op.mode,
+            source: op.source.clone(),
+            destination: op.destination.clone(),
+            group: op.group.clone(),
+        };
+        writeln!(self.file, "{}", serde_json::to_string(&record)?)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Whether a previous run left an unfinished intent log behind.
+pub fn pending(root: &Path) -> bool {
+    root.join(INTENT_LOG_FILE).exists()
+}
+
+/// Latest known state of every operation in the log, in plan order.
+fn load(root: &Path) -> Result<Vec<IntentRecord>> {
+    let file = File::open(root.join(INTENT_LOG_FILE))?;
+    let mut ops: BTreeMap<usize, IntentRecord> = BTreeMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        // A crash can leave the final line half-written
+        let Ok(record) = serde_json::from_str::<IntentRecord>(&line) else {
+            log::warn!("Ignoring truncated intent log line: {:?}", line);
+            continue;
+        };
+        ops.insert(record.seq, record);
+    }
+    Ok(ops.into_values().collect())
+}
+
+/// A started operation may or may not have reached the rename; the filesystem decides.
+fn completed(op: &IntentRecord) -> bool {
+    match (op.state, op.action) {
+        (_, PlanAction::Skip) => false,
+        (OpState::Done, _) => true,
+        (OpState::Started, PlanAction::RemoveSource) => !op.source.exists() && op.destination.exists(),
+        // The destination existed before the run, so only what it holds tells
+        (OpState::Started, PlanAction::Replace) => {
+            holds_source(op) || (!op.mode.keeps_source() && !op.source.exists() && op.destination.exists())
+        }
+        // The destination was free when the op started, and copies land under a
+        // temporary name, so an existing destination is a finished one. A
+        // cross-device move may have died before removing its source.
+        (OpState::Started, _) => fs::symlink_metadata(&op.destination).is_ok(),
+        (OpState::Planned, _) => false,
+    }
+}
+
+/// Whether the destination already is what placing the source produces: a copy
+/// with the same content, a hard link to it, or a symlink pointing at it.
+fn holds_source(op: &IntentRecord) -> bool {
+    match op.mode {
+        PlacementMode::Symlink => fs::read_link(&op.destination).is_ok_and(|target| target == op.source),
+        PlacementMode::Hardlink => same_inode(&op.source, &op.destination),
+        PlacementMode::Move | PlacementMode::Copy => {
+            op.source.is_file()
+                && op.destination.is_file()
+                && hash_path(&op.source).ok().is_some_and(|hash| hash_path(&op.destination).ok() == Some(hash))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn same_inode(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => (a.dev(), a.ino()) == (b.dev(), b.ino()),
+        _ => false,
+    }
+}
+
+#[cfg(windows)]
+fn same_inode(a: &Path, b: &Path) -> bool {
+    // Without file ids, equal content is the best available evidence
+    a.is_file() && b.is_file() && hash_path(a).ok().is_some_and(|hash| hash_path(b).ok() == Some(hash))
+}
+
+/// A completed move whose source is still there: the copy landed, the removal did not.
+fn source_left_behind(op: &IntentRecord) -> bool {
+    op.action != PlanAction::RemoveSource && !op.mode.keeps_source() && fs::symlink_metadata(&op.source).is_ok()
+}
+
+fn roll_back(op: &IntentRecord) -> Result<()> {
+    // Copies, links, and moves that never removed their source: the original is
+    // still in place, only the placed entry has to go
+    if op.action != PlanAction::RemoveSource && (op.mode.keeps_source() || source_left_behind(op)) {
+        transfer::remove_entry(&op.destination)?;
+        return Ok(());
+    }
+    if let Some(parent) = op.source.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if op.action == PlanAction::RemoveSource {
+        fs::copy(&op.destination, &op.source)?;
+    } else {
+        transfer::move_file(&op.destination, &op.source)?;
+    }
+    Ok(())
+}
+
+fn undo_action(op: &IntentRecord) -> UndoAction {
+    match op.action {
+        PlanAction::RemoveSource => UndoAction::RemovedDuplicate,
+        _ => UndoAction::from(op.mode),
+    }
+}
+
+/// Brings the root back to a consistent state after an interrupted run and removes
+/// the intent log.
+pub fn recover(root: &Path, action: RecoverAction) -> Result<()> {
+    if !pending(root) {
+        println!("Nothing to recover in {:?}", root);
+        return Ok(());
+    }
+    let ops = load(root)?;
+    let mut history = undo::load_history(root)?;
+    for op in &ops {
+        let removed = transfer::remove_partials(&op.destination)?;
+        if removed > 0 {
+            println!("Removed {} unfinished copy(ies) of {:?}", removed, op.destination);
+        }
+    }
+
+    match action {
+        RecoverAction::Finish => {
+            let session_id = history.begin_session(format!("recover {}", root.display()));
+            for op in ops.iter().filter(|op| op.action != PlanAction::Skip) {
+                if completed(op) {
+                    if source_left_behind(op) {
+                        transfer::remove_entry(&op.source)?;
+                        println!("Finished: removed {:?}, already copied to {:?}", op.source, op.destination);
+                    }
+                    // Done on disk but the run died before it reached the undo journal
+                    if !history.contains_move(&op.source, &op.destination) {
+                        history.record(root, session_id, undo_action(op), op.source.clone(), op.destination.clone(), group_of(op))?;
+                    }
+                    continue;
+                }
+                if !op.source.exists() {
+                    log::warn!("Skipping {:?}: source no longer exists", op.source);
+                    continue;
+                }
+                if let Some(parent) = op.destination.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let dest = match op.action {
+                    PlanAction::Replace => {
+                        plan::trash_replaced(&mut history, root, session_id, &op.destination, group_of(op))?;
+                        op.destination.clone()
+                    }
+                    PlanAction::RemoveSource if op.destination.exists() => {
+                        fs::remove_file(&op.source)?;
+                        history.record(root, session_id, UndoAction::RemovedDuplicate, op.source.clone(), op.destination.clone(), op.group.clone())?;
+                        println!("Finished: removed duplicate {:?}", op.source);
+                        continue;
+                    }
+                    _ => unique_path(&op.destination),
+                };
+                transfer::place(op.mode, &op.source, &dest)?;
+                history.record(root, session_id, undo_action(op), op.source.clone(), dest.clone(), group_of(op))?;
+                println!("Finished: {:?} -> {:?}", op.source, dest);
+            }
+        }
+        RecoverAction::Rollback => {
+            let mut failed = 0;
+            for op in ops.iter().rev().filter(|op| op.state != OpState::Planned) {
+                // Keep going so one stuck file does not strand the rest
+                if completed(op) {
+                    if let Err(e) = roll_back_completed(&mut history, op) {
+                        eprintln!("Failed to roll back {:?}: {}", op.destination, e);
+                        failed += 1;
+                        continue;
+                    }
+                }
+                // The older file a replace moved to the trash goes back once its place is free
+                if op.action == PlanAction::Replace && fs::symlink_metadata(&op.destination).is_err() {
+                    if let Some(location) = history.trashed_from(&op.destination) {
+                        if let Err(e) = trash::restore(&location, &op.destination) {
+                            eprintln!("Failed to restore {:?} from the trash: {}", op.destination, e);
+                            failed += 1;
+                            continue;
+                        }
+                        history.mark_undone(&op.destination, &location);
+                        println!("Restored from trash: {:?}", op.destination);
+                    }
+                }
+            }
+            undo::save_history(&history, root)?;
+            if failed > 0 {
+                bail!("{} operation(s) could not be rolled back; the intent log was kept", failed);
+            }
+        }
+    }
+
+    fs::remove_file(root.join(INTENT_LOG_FILE))?;
+    Ok(())
+}
+
+/// The journal group of an op: a replace shares one with the file it displaced.
+fn group_of(op: &IntentRecord) -> Option<PathBuf> {
+    match op.action {
+        PlanAction::Replace => op.group.clone().or_else(|| Some(op.source.clone())),
+        _ => op.group.clone(),
+    }
+}
+
+/// Undoes one operation that reached the disk. An occupied source is left alone.
+fn roll_back_completed(history: &mut undo::UndoHistory, op: &IntentRecord) -> Result<()> {
+    let restores_source =
+        op.action == PlanAction::RemoveSource || (!op.mode.keeps_source() && !source_left_behind(op));
+    if restores_source && op.source.exists() {
+        log::warn!("Cannot roll back {:?}: {:?} is occupied", op.destination, op.source);
+        return Ok(());
+    }
+    roll_back(op)?;
+    history.mark_undone(&op.source, &op.destination);
+    println!("Rolled back: {:?} -> {:?}", op.destination, op.source);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op(root: &Path, source: &str, destination: &str, action: PlanAction, mode: PlacementMode) -> PlannedMove {
+        PlannedMove {
+            source: root.join(source),
+            destination: root.join(destination),
+            category: "Docs".to_string(),
+            reason: "test".to_string(),
+            action,
+            mode,
+            collision: None,
+            group: None,
+            size: 0,
+            modified: None,
+        }
+    }
+
+    /// A run that died with one op in every state:
+    /// - `a.txt` was moved and journaled;
+    /// - `b.txt` died mid cross-device copy, leaving a partial copy;
+    /// - `c.txt` was copied across but its source was never removed;
+    /// - `d.txt` was only planned.
+    fn interrupted_run() -> (tempfile::TempDir, PathBuf) {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(tmp.path()).unwrap();
+        fs::create_dir(root.join("Docs")).unwrap();
+        for name in ["a", "b", "c", "d"] {
+            fs::write(root.join(format!("{}.txt", name)), name).unwrap();
+        }
+        let ops: Vec<PlannedMove> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|name| op(&root, &format!("{}.txt", name), &format!("Docs/{}.txt", name), PlanAction::Move, PlacementMode::Move))
+            .collect();
+
+        let mut log = IntentLog::create(&root).unwrap();
+        for (seq, op) in ops.iter().enumerate() {
+            log.planned(seq, op).unwrap();
+        }
+        log.started(0, &ops[0]).unwrap();
+        fs::rename(root.join("a.txt"), root.join("Docs/a.txt")).unwrap();
+        let mut history = undo::load_history(&root).unwrap();
+        let session = history.begin_session("organize".to_string());
+        history.record(&root, session, UndoAction::Move, root.join("a.txt"), root.join("Docs/a.txt"), None).unwrap();
+        log.done(0, &ops[0]).unwrap();
+
+        log.started(1, &ops[1]).unwrap();
+        fs::write(root.join("Docs/.b.txt.partial-12345"), "b").unwrap();
+
+        log.started(2, &ops[2]).unwrap();
+        fs::copy(root.join("c.txt"), root.join("Docs/c.txt")).unwrap();
+        drop(log);
+        (tmp, root)
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> =
+            fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn finish_completes_every_operation_once() {
+        let (_tmp, root) = interrupted_run();
+        assert!(pending(&root));
+        recover(&root, RecoverAction::Finish).unwrap();
+
+        assert!(!pending(&root));
+        assert_eq!(names(&root.join("Docs")), ["a.txt", "b.txt", "c.txt", "d.txt"]);
+        for name in ["a", "b", "c", "d"] {
+            assert!(!root.join(format!("{}.txt", name)).exists());
+            assert_eq!(fs::read_to_string(root.join(format!("Docs/{}.txt", name))).unwrap(), name);
+        }
+
+        let history = undo::load_history(&root).unwrap();
+        let entries: Vec<_> = history.sessions.iter().flat_map(|s| &s.entries).collect();
+        assert_eq!(entries.len(), 4, "the journaled move is not recorded twice");
+        for name in ["a", "b", "c", "d"] {
+            assert!(history.contains_move(&root.join(format!("{}.txt", name)), &root.join(format!("Docs/{}.txt", name))));
+        }
+        assert!(entries.iter().all(|e| !e.undone));
+    }
+
+    #[test]
+    fn rollback_restores_the_tree_before_the_run() {
+        let (_tmp, root) = interrupted_run();
+        recover(&root, RecoverAction::Rollback).unwrap();
+
+        assert!(!pending(&root));
+        assert!(names(&root.join("Docs")).is_empty());
+        for name in ["a", "b", "c", "d"] {
+            assert_eq!(fs::read_to_string(root.join(format!("{}.txt", name))).unwrap(), name);
+        }
+
+        let history = undo::load_history(&root).unwrap();
+        let entries: Vec<_> = history.sessions.iter().flat_map(|s| &s.entries).collect();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].undone);
+    }
+
+    #[test]
+    fn rollback_keeps_a_destination_that_was_never_replaced() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(tmp.path()).unwrap();
+        fs::write(root.join("new.txt"), "new").unwrap();
+        fs::write(root.join("old.txt"), "precious").unwrap();
+        let replace = op(&root, "new.txt", "old.txt", PlanAction::Replace, PlacementMode::Copy);
+        let mut log = IntentLog::create(&root).unwrap();
+        log.planned(0, &replace).unwrap();
+        log.started(0, &replace).unwrap();
+        drop(log);
+
+        recover(&root, RecoverAction::Rollback).unwrap();
+        assert_eq!(fs::read_to_string(root.join("old.txt")).unwrap(), "precious");
+        assert_eq!(fs::read_to_string(root.join("new.txt")).unwrap(), "new");
+    }
+
+    #[test]
+    fn truncated_last_line_is_ignored() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(tmp.path()).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let planned = op(&root, "a.txt", "b.txt", PlanAction::Move, PlacementMode::Move);
+        let mut log = IntentLog::create(&root).unwrap();
+        log.planned(0, &planned).unwrap();
+        drop(log);
+        let mut file = OpenOptions::new().append(true).open(root.join(INTENT_LOG_FILE)).unwrap();
+        write!(file, "{{\"seq\":0,\"state\":\"sta").unwrap();
+
+        let ops = load(&root).unwrap();
+        assert_eq!(ops.len(), 1);
+        assert_eq!(ops[0].state, OpState::Planned);
+    }
+}
@@ -1,8 +1,12 @@
-use notify::{Watcher, RecursiveMode, Result, Event};
+use crate::constants::IGNORE_FILE;
+use crate::organizer::scanner::ScanOptions;
+use anyhow::Result;
+use notify::{Watcher, RecursiveMode, Event};
 use std::path::Path;
 use std::sync::mpsc::channel;
 
-pub fn watch_folder(path: &Path) -> Result<()> {
+pub fn watch_folder(path: &Path, options: &ScanOptions) -> Result<()> {
+    let mut filter = options.path_filter(path)?;
     let (tx, rx) = channel();
 
     let mut watcher = notify::recommended_watcher(tx)?;
@@ -13,8 +17,13 @@ pub fn watch_folder(path: &Path) -> Result<()> {
     for res in rx {
         match res {
             Ok(event) => {
-                if is_create_event(&event) {
-                    println!("New file detected: {:?}", event.paths);
+                if touches_ignore_files(&event) {
+                    log::info!("{} changed; reloading ignore rules", IGNORE_FILE);
+                    filter = options.path_filter(path)?;
+                }
+                let paths: Vec<_> = event.paths.iter().filter(|p| !filter.is_ignored(p)).collect();
+                if is_create_event(&event) && !paths.is_empty() {
+                    println!("New file detected: {:?}", paths);
                     // Add logic to trigger organization
                 }
             }
@@ -28,3 +37,11 @@ pub fn watch_folder(path: &Path) -> Result<()> {
 fn is_create_event(event: &Event) -> bool {
     matches!(event.kind, notify::EventKind::Create(_))
 }
+
+/// An ignore file was written, removed or renamed, or a folder holding one moved in.
+fn touches_ignore_files(event: &Event) -> bool {
+    event.paths.iter().any(|p| {
+        p.file_name().is_some_and(|name| name == IGNORE_FILE)
+            || (is_create_event(event) && p.join(IGNORE_FILE).is_file())
+    })
+}